@@ -0,0 +1,148 @@
+// Computer Science 365: VM Project, Machine
+// Mike Hall, Seth Nelson, Sarah Pastor, Alan Saucer
+
+// Values on the stack are full 32-bit words, only push immediates are 28 bits
+
+mod common;
+
+use common::machine;
+use machine::{ArithmeticMode, ExitReason, Instruction, PrintFormat, VmError};
+
+// Run program and return what it printed
+fn output(program: &[Instruction], input: &str) -> String {
+    let mut m = machine(program, input);
+    m.run();
+    String::from_utf8(m.into_io().into_output()).unwrap()
+}
+
+fn print_hex() -> Instruction {
+    Instruction::Print { offset: 0, format: PrintFormat::Hex }
+}
+
+#[test]
+fn push_immediates_are_sign_extended() {
+    let program = [
+        Instruction::Push { value: -1 },
+        print_hex(),
+        Instruction::Push { value: -0x0800_0000 },
+        print_hex(),
+        Instruction::Push { value: 0x07ff_ffff },
+        print_hex(),
+    ];
+    assert_eq!(output(&program, ""), "0xffffffff\n0xf8000000\n0x7ffffff\n");
+}
+
+// Results with bit 27 set used to be sign-extended as if they were immediates
+#[test]
+fn results_above_2_pow_27_keep_all_32_bits() {
+    let cases = [
+        (Instruction::Add, 0x07ff_ffff, 1, "0x8000000"),
+        (Instruction::Mul, 0x0400_0000, 4, "0x10000000"),
+        (Instruction::Mul, 0x0400_0000, 2, "0x8000000"),
+        (Instruction::Sub, 0, -0x0800_0000, "0x8000000"),
+        (Instruction::Lsl, 1, 27, "0x8000000"),
+        (Instruction::Lsl, 0x0555_5555, 4, "0x55555550"),
+    ];
+    for (op, left, right, expected) in cases {
+        let program = [Instruction::Push { value: left }, Instruction::Push { value: right }, op, print_hex()];
+        assert_eq!(output(&program, ""), format!("{}\n", expected), "{} {} {}", left, op, right);
+    }
+}
+
+#[test]
+fn input_above_2_pow_27_stays_positive() {
+    let program = [Instruction::Input, Instruction::Print { offset: 0, format: PrintFormat::Decimal }];
+    assert_eq!(output(&program, "0x08000000\n"), "134217728\n");
+    assert_eq!(output(&program, "2000000000\n"), "2000000000\n");
+}
+
+// (value, count, lsl, lsr, asr)
+const SHIFTS: &[(i32, i32, u32, u32, u32)] = &[
+    (1, 0, 0x0000_0001, 0x0000_0001, 0x0000_0001),
+    (1, 31, 0x8000_0000, 0x0000_0000, 0x0000_0000),
+    (0x1234_5678, 4, 0x2345_6780, 0x0123_4567, 0x0123_4567),
+    (0x7fff_ffff, 1, 0xffff_fffe, 0x3fff_ffff, 0x3fff_ffff),
+    (-1, 1, 0xffff_fffe, 0x7fff_ffff, 0xffff_ffff),
+    (-1, 31, 0x8000_0000, 0x0000_0001, 0xffff_ffff),
+    (-16, 2, 0xffff_ffc0, 0x3fff_fffc, 0xffff_fffc),
+    (i32::MIN, 1, 0x0000_0000, 0x4000_0000, 0xc000_0000),
+    (i32::MIN, 31, 0x0000_0000, 0x0000_0001, 0xffff_ffff),
+    (-0x0800_0000, 4, 0x8000_0000, 0x0f80_0000, 0xff80_0000),
+    // Counts of 32 and up, and negative counts, shift everything out
+    (0x1234_5678, 32, 0, 0, 0),
+    (0x1234_5678, 33, 0, 0, 0),
+    (0x1234_5678, 1000, 0, 0, 0),
+    (0x1234_5678, -1, 0, 0, 0),
+    (-5, 32, 0, 0, 0xffff_ffff),
+    (-5, 0x0700_0000, 0, 0, 0xffff_ffff),
+    (-5, -3, 0, 0, 0xffff_ffff),
+];
+
+#[test]
+fn shifts() {
+    for &(value, count, lsl, lsr, asr) in SHIFTS {
+        for (op, expected) in [(Instruction::Lsl, lsl), (Instruction::Lsr, lsr), (Instruction::Asr, asr)] {
+            // Read both operands, most of these don't fit in a push immediate
+            let program = [Instruction::Input, Instruction::Input, op, print_hex()];
+            let input = format!("{}\n{}\n", value, count);
+            assert_eq!(output(&program, &input), format!("0x{:x}\n", expected), "{} {} {}", value, op, count);
+        }
+    }
+}
+
+// Apply op to operands (read with Input, so any 32-bit value works) under mode
+fn evaluate(mode: ArithmeticMode, op: Instruction, operands: &[i32]) -> Result<i32, VmError> {
+    let mut program = vec![Instruction::Input; operands.len()];
+    program.extend([op, Instruction::Print { offset: 0, format: PrintFormat::Decimal }]);
+    let input: String = operands.iter().map(|operand| format!("{}\n", operand)).collect();
+
+    let mut m = machine(&program, &input);
+    m.set_arithmetic_mode(mode);
+    match m.run() {
+        ExitReason::Fault(fault) => Err(fault.error),
+        _ => Ok(String::from_utf8(m.into_io().into_output()).unwrap().trim().parse().unwrap()),
+    }
+}
+
+const MAX: i32 = i32::MAX;
+const MIN: i32 = i32::MIN;
+
+// (op, operands, wrapping, checked, saturating), None is an overflow fault
+#[allow(clippy::type_complexity)]
+const OVERFLOWS: &[(Instruction, &[i32], i32, Option<i32>, i32)] = &[
+    (Instruction::Add, &[2, 3], 5, Some(5), 5),
+    (Instruction::Add, &[MAX, 1], MIN, None, MAX),
+    (Instruction::Add, &[MIN, -1], MAX, None, MIN),
+    (Instruction::Sub, &[MIN, 1], MAX, None, MIN),
+    (Instruction::Sub, &[0, MIN], MIN, None, MAX),
+    (Instruction::Sub, &[-1, MIN], MAX, Some(MAX), MAX),
+    (Instruction::Mul, &[-6, 7], -42, Some(-42), -42),
+    (Instruction::Mul, &[0x10000, 0x10000], 0, None, MAX),
+    (Instruction::Mul, &[0x10000, -0x10000], 0, None, MIN),
+    (Instruction::Mul, &[MIN, -1], MIN, None, MAX),
+    (Instruction::Div, &[-7, 2], -3, Some(-3), -3),
+    (Instruction::Div, &[MIN, -1], MIN, None, MAX),
+    (Instruction::Rem, &[-7, 2], -1, Some(-1), -1),
+    (Instruction::Rem, &[MIN, -1], 0, Some(0), 0),
+    (Instruction::Neg, &[5], -5, Some(-5), -5),
+    (Instruction::Neg, &[MAX], -MAX, Some(-MAX), -MAX),
+    (Instruction::Neg, &[MIN], MIN, None, MAX),
+];
+
+#[test]
+fn arithmetic_modes() {
+    for &(op, operands, wrapping, checked, saturating) in OVERFLOWS {
+        let checked = checked.ok_or(VmError::Overflow);
+        assert_eq!(evaluate(ArithmeticMode::Wrapping, op, operands), Ok(wrapping), "wrapping {} {:?}", op, operands);
+        assert_eq!(evaluate(ArithmeticMode::Checked, op, operands), checked, "checked {} {:?}", op, operands);
+        assert_eq!(evaluate(ArithmeticMode::Saturating, op, operands), Ok(saturating), "saturating {} {:?}", op, operands);
+    }
+}
+
+#[test]
+fn divide_by_zero_faults_in_every_mode() {
+    for mode in [ArithmeticMode::Wrapping, ArithmeticMode::Checked, ArithmeticMode::Saturating] {
+        assert_eq!(evaluate(mode, Instruction::Div, &[1, 0]), Err(VmError::DivideByZero), "{}", mode);
+        assert_eq!(evaluate(mode, Instruction::Rem, &[MIN, 0]), Err(VmError::DivideByZero), "{}", mode);
+    }
+}
@@ -0,0 +1,20 @@
+// Computer Science 365: VM Project, Machine
+// Mike Hall, Seth Nelson, Sarah Pastor, Alan Saucer
+
+// Fixtures shared by the Rust tests, each test file pulls them in with `mod common;`
+// Not every file uses every helper.
+#![allow(dead_code)]
+
+use machine::{BufferIo, Instruction, Machine};
+
+// The bytes the assembler would write for program
+pub fn assemble(program: &[Instruction]) -> Vec<u8> {
+    program.iter().flat_map(|instruction| instruction.encode()).collect()
+}
+
+// A default-sized machine with program loaded and input waiting to be read
+pub fn machine(program: &[Instruction], input: &str) -> Machine<BufferIo> {
+    let mut m = Machine::new().with_io(BufferIo::new(input.as_bytes().to_vec()));
+    m.load_bytes(&assemble(program)).unwrap();
+    m
+}
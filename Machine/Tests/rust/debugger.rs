@@ -0,0 +1,154 @@
+// Computer Science 365: VM Project, Machine
+// Mike Hall, Seth Nelson, Sarah Pastor, Alan Saucer
+
+// The debugger driven through a BufferIo console instead of the terminal
+
+mod common;
+
+use common::machine;
+use machine::{BufferIo, Cond, Debugger, ExitReason, Instruction, UnaryCond, WatchTarget};
+
+fn debugger(commands: &str) -> Debugger<BufferIo> {
+    Debugger::with_console(BufferIo::new(commands.as_bytes().to_vec()))
+}
+
+// Everything the debugger said
+fn shown(debugger: &Debugger<BufferIo>) -> String {
+    String::from_utf8(debugger.console().output().to_vec()).unwrap()
+}
+
+// Count down from 2, going round the loop at 4 twice
+fn countdown() -> Vec<Instruction> {
+    vec![
+        Instruction::Push { value: 2 },                             // 0
+        Instruction::Push { value: 1 },                             // 4
+        Instruction::Sub,                                           // 8
+        Instruction::UnaryIf { cond: UnaryCond::Nz, offset: -8 },   // 12
+        Instruction::Exit { code: 4 },                              // 16
+    ]
+}
+
+#[test]
+fn breakpoint_stops_and_continue_resumes() {
+    let mut m = machine(&[
+        Instruction::Push { value: 1 },
+        Instruction::Push { value: 2 },
+        Instruction::BinaryIf { cond: Cond::Lt, offset: 8 },
+        Instruction::Exit { code: 1 },
+        Instruction::Exit { code: 2 },
+    ], "");
+    let mut debugger = debugger("c\n");
+    debugger.add_breakpoint(8);
+    assert_eq!(debugger.run(&mut m), ExitReason::Exit(2));
+    let shown = shown(&debugger);
+    assert!(shown.starts_with("breakpoint at PC=0x0008\nPC=0x0008 SP=0x0ff8\n"), "{}", shown);
+    assert_eq!(shown.matches("breakpoint at").count(), 1);
+}
+
+// Continuing from a breakpoint runs its instruction instead of stopping on it
+// again, but the next trip round the loop stops there
+#[test]
+fn breakpoint_is_not_hit_again_when_resuming() {
+    let mut m = machine(&countdown(), "");
+    let mut debugger = debugger("c\nc\n");
+    debugger.add_breakpoint(8);
+    assert_eq!(debugger.run(&mut m), ExitReason::Exit(4));
+    assert_eq!(shown(&debugger).matches("breakpoint at PC=0x0008").count(), 2);
+}
+
+#[test]
+fn address_watchpoint_fires_on_change() {
+    let mut m = machine(&[
+        Instruction::Push { value: 5 },     // writes 0x0ffc
+        Instruction::Push { value: 6 },     // writes 0x0ff8
+        Instruction::Exit { code: 0 },
+    ], "");
+    let mut debugger = debugger("c\n");
+    debugger.add_watchpoint(WatchTarget::Address(0x0ffc));
+    assert_eq!(debugger.run(&mut m), ExitReason::Exit(0));
+    let shown = shown(&debugger);
+    assert!(shown.starts_with("push 5 at PC=0x0000\nwatchpoint 0x0ffc: 00000000 -> 00000005\n"), "{}", shown);
+    assert_eq!(shown.matches("watchpoint").count(), 1);
+}
+
+// sp+4 follows SP: it is off the end of RAM until two words are pushed
+#[test]
+fn stack_watchpoint_follows_sp() {
+    let mut m = machine(&[
+        Instruction::Push { value: 1 },
+        Instruction::Push { value: 2 },
+        Instruction::Push { value: 3 },
+        Instruction::Exit { code: 0 },
+    ], "");
+    let mut debugger = debugger("c\nc\n");
+    debugger.add_watchpoint(WatchTarget::parse("sp+4").unwrap());
+    assert_eq!(debugger.run(&mut m), ExitReason::Exit(0));
+    let changes: Vec<String> = shown(&debugger)
+        .lines()
+        .filter(|line| line.starts_with("watchpoint"))
+        .map(str::to_string)
+        .collect();
+    assert_eq!(changes, ["watchpoint sp+4: -------- -> 00000001", "watchpoint sp+4: 00000001 -> 00000002"]);
+}
+
+// Stops at the debug instruction, then walks through the prompt commands
+#[test]
+fn debug_instruction_opens_the_prompt() {
+    let mut m = machine(&[
+        Instruction::Push { value: -2 },        // 0
+        Instruction::Push { value: 7 },         // 4
+        Instruction::Debug { value: 0xdead },   // 8
+        Instruction::Add,                       // 12
+        Instruction::Exit { code: 0 },          // 16
+    ], "");
+    let mut debugger = debugger("stack\np sp+4\nx 0x0ff8 2\ns\nq\n");
+    assert_eq!(debugger.run(&mut m), ExitReason::Quit);
+    assert_eq!(m.get_program_counter(), 16);
+    assert_eq!(m.peek(0x0ffc), 5);
+    let expected = "\
+debug 0xdead at PC=0x0008
+PC=0x000c SP=0x0ff8
+  sp+0    0ff8: 00000007
+  sp+4    0ffc: fffffffe
+(debug)   sp+0    0ff8: 00000007
+  sp+4    0ffc: fffffffe
+(debug)   0ffc: -2 (0xfffffffe)
+(debug)   0ff8: 00000007
+  0ffc: fffffffe
+(debug) stepped add at PC=0x000c
+PC=0x0010 SP=0x0ffc
+  sp+0    0ffc: 00000005
+(debug) ";
+    assert_eq!(shown(&debugger), expected);
+}
+
+// Same syntax as the disassembly, a bare debug has no operand
+#[test]
+fn bare_debug_stop_matches_the_disassembly() {
+    let mut m = machine(&[Instruction::Debug { value: 0 }, Instruction::Exit { code: 0 }], "");
+    let mut debugger = debugger("q\n");
+    assert_eq!(debugger.run(&mut m), ExitReason::Quit);
+    assert!(shown(&debugger).starts_with("debug at PC=0x0000\n"), "{}", shown(&debugger));
+}
+
+#[test]
+fn step_runs_n_instructions() {
+    let mut m = machine(&countdown(), "");
+    let mut debugger = debugger("s 3\nq\n");
+    debugger.add_breakpoint(0);
+    assert_eq!(debugger.run(&mut m), ExitReason::Quit);
+    assert_eq!(m.get_program_counter(), 12);
+    assert!(shown(&debugger).contains("stepped sub at PC=0x0008\nPC=0x000c SP=0x0ffc\n"), "{}", shown(&debugger));
+}
+
+#[test]
+fn bad_commands_are_reported() {
+    let mut m = machine(&countdown(), "");
+    let mut debugger = debugger("p nowhere\nx sp 0\ns 0\nfrobnicate\nq\n");
+    debugger.add_breakpoint(0);
+    assert_eq!(debugger.run(&mut m), ExitReason::Quit);
+    let shown = shown(&debugger);
+    for message in ["bad address\n", "bad address or count\n", "step count must be a positive number\n", "unknown command"] {
+        assert!(shown.contains(message), "{}", shown);
+    }
+}
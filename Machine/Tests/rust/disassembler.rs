@@ -0,0 +1,69 @@
+// Computer Science 365: VM Project, Machine
+// Mike Hall, Seth Nelson, Sarah Pastor, Alan Saucer
+
+// Disassembly without symbols, checked against the .asm it was assembled from
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use machine::helper::parse_number;
+use machine::disassembler::disassemble;
+use machine::Image;
+
+fn tests_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("Tests")
+}
+
+const DEBUG: &str = "\
+0x0000: f001010a  push 65802
+0x0004: f121646c  push 18965612
+0x0008: f1726f57  push 24276823
+0x000c: f1206f6c  push 18902892
+0x0010: f16c6548  push 23881032
+0x0014: 40000000  stprint 0
+0x0018: 0f000000  debug
+0x001c: 1000beec  pop 48876
+0x0020: 0f00dead  debug 0xdead
+0x0024: 00000000  exit 0
+0x0028: 02000000  nop
+0x002c: 02000000  nop
+";
+
+#[test]
+fn plain_disassembly() {
+    let image = Image::from_file(tests_dir().join("v").join("debug.v")).unwrap();
+    assert_eq!(disassemble(&image.code), DEBUG);
+}
+
+// Mnemonic and numeric operands, with a left out operand counting as 0
+fn meaning(statement: &str) -> (String, Vec<i64>) {
+    let mut words = statement.split_whitespace();
+    let mnemonic = words.next().unwrap().to_string();
+    let mut operands: Vec<i64> = words.map(|word| parse_number(word).unwrap()).collect();
+    if operands.is_empty() {
+        operands.push(0);
+    }
+    (mnemonic, operands)
+}
+
+// Every statement in debug.asm after its stpush reads back the same from the disassembly
+#[test]
+fn disassembly_matches_the_source() {
+    let source = fs::read_to_string(tests_dir().join("MarzFiles").join("debug.asm")).unwrap();
+    let statements: Vec<&str> = source
+        .lines()
+        .map(|line| line.split('#').next().unwrap().trim())
+        .filter(|line| !line.is_empty() && !line.ends_with(':') && !line.starts_with("stpush"))
+        .collect();
+
+    let image = Image::from_file(tests_dir().join("v").join("debug.v")).unwrap();
+    let disassembly = disassemble(&image.code);
+    // The 13 characters of "Hello World!\n" took five pushes
+    let instructions: Vec<&str> = disassembly.lines().skip(5).map(|line| &line[18..]).collect();
+
+    assert_eq!(statements.len(), 5);
+    for (statement, instruction) in statements.iter().zip(&instructions) {
+        assert_eq!(meaning(statement), meaning(instruction), "{} / {}", statement, instruction);
+    }
+    assert_eq!(statements[1], instructions[1]);  // a bare debug stays bare
+}
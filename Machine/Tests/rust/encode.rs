@@ -0,0 +1,136 @@
+// Computer Science 365: VM Project, Machine
+// Mike Hall, Seth Nelson, Sarah Pastor, Alan Saucer
+
+// Instruction::encode against known words and as the inverse of decode_instruction
+
+use std::fs;
+use std::path::Path;
+
+use machine::{Cond, Image, Instruction, PrintFormat, UnaryCond};
+
+// (instruction, word the assembler produces for it)
+fn known_words() -> Vec<(Instruction, u32)> {
+    vec![
+        (Instruction::Exit { code: 0 }, 0x0000_0000),
+        (Instruction::Exit { code: 123 }, 0x0000_007b),
+        (Instruction::Swap { from: -4, to: 0 }, 0x01ff_f000),
+        (Instruction::Swap { from: -8, to: -4 }, 0x01ff_efff),
+        (Instruction::Nop, 0x0200_0000),
+        (Instruction::Input, 0x0400_0000),
+        (Instruction::StInput { max_chars: 0x00ff_ffff }, 0x05ff_ffff),
+        (Instruction::Debug { value: 0xdead }, 0x0f00_dead),
+        (Instruction::Pop { offset: 0xbeec }, 0x1000_beec),
+        (Instruction::Pop { offset: -8 }, 0x1fff_fff8),
+        (Instruction::Add, 0x2000_0000),
+        (Instruction::Sub, 0x2100_0000),
+        (Instruction::Mul, 0x2200_0000),
+        (Instruction::Div, 0x2300_0000),
+        (Instruction::Rem, 0x2400_0000),
+        (Instruction::And, 0x2500_0000),
+        (Instruction::Or, 0x2600_0000),
+        (Instruction::Xor, 0x2700_0000),
+        (Instruction::Lsl, 0x2800_0000),
+        (Instruction::Lsr, 0x2900_0000),
+        (Instruction::Asr, 0x2b00_0000),
+        (Instruction::Neg, 0x3000_0000),
+        (Instruction::Not, 0x3100_0000),
+        (Instruction::StPrint { offset: 0 }, 0x4000_0000),
+        (Instruction::Call { offset: 64 }, 0x5000_0040),
+        (Instruction::Return { offset: 28 }, 0x6000_001c),
+        (Instruction::Goto { offset: -12 }, 0x7fff_fff4),
+        (Instruction::BinaryIf { cond: Cond::Ne, offset: -12 }, 0x83ff_fff4),
+        (Instruction::UnaryIf { cond: UnaryCond::Pl, offset: 8 }, 0x9600_0008),
+        (Instruction::Dup { offset: 4 }, 0xc000_0004),
+        (Instruction::Dup { offset: -4 }, 0xcfff_fffc),
+        (Instruction::Print { offset: 4, format: PrintFormat::Decimal }, 0xd000_0004),
+        (Instruction::Print { offset: 4, format: PrintFormat::Hex }, 0xd000_0005),
+        (Instruction::Print { offset: 8, format: PrintFormat::Octal }, 0xd000_000b),
+        (Instruction::Print { offset: -32, format: PrintFormat::Decimal }, 0xdfff_ffe0),
+        (Instruction::Print { offset: -0x0800_0000, format: PrintFormat::Binary }, 0xd800_0002),
+        (Instruction::Dump, 0xe000_0000),
+        (Instruction::Push { value: 5 }, 0xf000_0005),
+        (Instruction::Push { value: -1 }, 0xffff_ffff),
+        (Instruction::Push { value: 0x07ff_ffff }, 0xf7ff_ffff),            // largest immediate
+        (Instruction::Push { value: -0x0800_0000 }, 0xf800_0000),           // smallest immediate
+    ]
+}
+
+#[test]
+fn encode_produces_known_words() {
+    for (instruction, word) in known_words() {
+        assert_eq!(instruction.encode(), word.to_le_bytes(), "{:?}", instruction);
+    }
+}
+
+#[test]
+fn decode_inverts_encode() {
+    for (instruction, _) in known_words() {
+        let decoded = Instruction::decode_instruction(&instruction.encode());
+        assert_eq!(decoded, Some(instruction));
+    }
+}
+
+// Operands wider than their field, or offsets that aren't whole words, can't
+// come back out of decode the same
+fn out_of_range() -> Vec<Instruction> {
+    vec![
+        Instruction::Swap { from: 8192, to: 0 },
+        Instruction::Swap { from: 0, to: 2 },
+        Instruction::StInput { max_chars: 0x0100_0000 },
+        Instruction::Debug { value: 0x0100_0000 },
+        Instruction::Pop { offset: 0x0800_0000 },
+        Instruction::StPrint { offset: 6 },
+        Instruction::Call { offset: 0x0800_0000 },
+        Instruction::Return { offset: -0x0800_0004 },
+        Instruction::Goto { offset: 1 },
+        Instruction::BinaryIf { cond: Cond::Eq, offset: 0x0100_0000 },
+        Instruction::UnaryIf { cond: UnaryCond::Mi, offset: -0x0100_0004 },
+        Instruction::Dup { offset: 3 },
+        Instruction::Print { offset: 0x0800_0000, format: PrintFormat::Hex },
+        Instruction::Push { value: 0x0800_0000 },
+        Instruction::Push { value: -0x0800_0001 },
+    ]
+}
+
+#[test]
+fn known_words_fit_their_encoding() {
+    for (instruction, _) in known_words() {
+        assert!(instruction.fits_encoding(), "{:?}", instruction);
+    }
+}
+
+#[test]
+fn out_of_range_operands_do_not_fit() {
+    for instruction in out_of_range() {
+        assert!(!instruction.fits_encoding(), "{:?}", instruction);
+    }
+}
+
+#[test]
+#[should_panic(expected = "has an operand its encoding can't hold")]
+fn encode_rejects_an_out_of_range_operand() {
+    Instruction::Push { value: 0x0800_0000 }.encode();
+}
+
+// Every word the assembler produced for our test programs survives a round trip
+#[test]
+fn test_programs_round_trip() {
+    let tests = Path::new(env!("CARGO_MANIFEST_DIR")).join("Tests");
+    let mut words = 0;
+    for dir in ["v", "tests"] {
+        for entry in fs::read_dir(tests.join(dir)).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_none_or(|ext| ext != "v") {
+                continue;
+            }
+            let image = Image::from_file(&path).unwrap();
+            for (index, chunk) in image.code.chunks_exact(4).enumerate() {
+                if let Some(instruction) = Instruction::decode_instruction(chunk) {
+                    assert_eq!(instruction.encode(), chunk, "{} word {}: {}", path.display(), index, instruction);
+                    words += 1;
+                }
+            }
+        }
+    }
+    assert!(words > 0);
+}
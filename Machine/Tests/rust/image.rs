@@ -0,0 +1,100 @@
+// Computer Science 365: VM Project, Machine
+// Mike Hall, Seth Nelson, Sarah Pastor, Alan Saucer
+
+// The .v container format, and legacy headerless files next to it
+
+use std::fs;
+use std::path::Path;
+
+mod common;
+
+use common::assemble;
+use machine::{BufferIo, DataSegment, ExitReason, Image, Instruction, LoadError, Machine, Symbols};
+
+fn word(value: u32) -> [u8; 4] {
+    value.to_le_bytes()
+}
+
+#[test]
+fn legacy_files_still_load() {
+    let mut bytes = vec![0xde, 0xad, 0xbe, 0xef];
+    bytes.extend_from_slice(&word(0xf000_0005));
+    let image = Image::from_bytes(&bytes).unwrap();
+    assert_eq!(image, Image::new(word(0xf000_0005).to_vec()));
+}
+
+#[test]
+fn container_round_trips() {
+    let image = Image {
+        code: assemble(&[Instruction::Nop, Instruction::Exit { code: 2 }]),
+        entry: 4,
+        data: vec![DataSegment { address: 0x800, bytes: b"hello".to_vec() }],
+        symbols: Some(b"label main 0x0004\n".to_vec()),
+    };
+    let symbols = Symbols::parse(std::str::from_utf8(image.symbols.as_ref().unwrap()).unwrap()).unwrap();
+    assert_eq!(symbols.address_of("main"), Some(4));
+    let bytes = image.to_bytes();
+    assert_eq!(&bytes[4..8], &word(0xa000_0002));
+    assert_eq!(bytes.len() % 4, 0);
+    assert_eq!(Image::from_bytes(&bytes).unwrap(), image);
+}
+
+// Every test program wrapped in a container comes back out unchanged
+#[test]
+fn test_programs_round_trip() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("Tests").join("v");
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        let image = Image::from_file(&path).unwrap();
+        assert_eq!(Image::from_bytes(&image.to_bytes()).unwrap(), image, "{}", path.display());
+    }
+}
+
+#[test]
+fn machine_starts_at_entry_with_data_loaded() {
+    let image = Image {
+        code: assemble(&[
+            Instruction::Exit { code: 1 },
+            Instruction::StPrint { offset: 0x800 - 4096 },  // the data segment
+            Instruction::Exit { code: 0 },
+        ]),
+        entry: 4,
+        data: vec![DataSegment { address: 0x800, bytes: b"hi\0".to_vec() }],
+        symbols: None,
+    };
+    let mut m = Machine::new().with_io(BufferIo::new(Vec::new()));
+    m.load_image(&image).unwrap();
+    assert_eq!(m.run(), ExitReason::Exit(0));
+    assert_eq!(m.io().output(), b"hi");
+}
+
+#[test]
+fn bad_containers_are_rejected() {
+    let header = |version: u32, rest: &[u32]| {
+        let mut bytes = vec![0xde, 0xad, 0xbe, 0xef];
+        bytes.extend_from_slice(&word(0xa000_0000 | version));
+        for value in rest {
+            bytes.extend_from_slice(&word(*value));
+        }
+        bytes
+    };
+    assert!(matches!(Image::from_bytes(&header(9, &[0, 0])), Err(LoadError::UnsupportedVersion(9))));
+    assert!(matches!(Image::from_bytes(&header(2, &[0])), Err(LoadError::Truncated)));
+    assert!(matches!(Image::from_bytes(&header(2, &[0, 1, 1, 0, 8])), Err(LoadError::Truncated)));
+    assert!(matches!(Image::from_bytes(&header(2, &[2, 0])), Err(LoadError::BadEntry(2))));
+    assert!(matches!(Image::from_bytes(&header(2, &[4, 0])), Err(LoadError::EntryOutsideCode { entry: 4, code_len: 0 })));
+    assert!(matches!(
+        Image::from_bytes(&header(2, &[8, 1, 1, 0, 8, 0x0200_0000, 0])),
+        Err(LoadError::EntryOutsideCode { entry: 8, code_len: 8 })
+    ));
+    assert!(Image::from_bytes(&header(2, &[4, 1, 1, 0, 8, 0x0200_0000, 0])).is_ok());
+    assert!(matches!(
+        Image::from_bytes(&header(2, &[0, 1, 7, 0, 0])),
+        Err(LoadError::BadSection { kind: 7, address: 0 })
+    ));
+
+    let mut image = Image::new(Vec::new());
+    image.data.push(DataSegment { address: 4094, bytes: vec![0; 4] });
+    let mut m = Machine::new().with_io(BufferIo::new(Vec::new()));
+    assert!(matches!(m.load_image(&image), Err(LoadError::DoesNotFit { address: 4094, len: 4 })));
+}
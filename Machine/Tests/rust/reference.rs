@@ -0,0 +1,89 @@
+// Computer Science 365: VM Project, Machine
+// Mike Hall, Seth Nelson, Sarah Pastor, Alan Saucer
+
+// Runs every Tests/v program against its Tests/input files in-process and
+// compares with Tests/output, the same pairing gradescript.py uses.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use machine::{BufferIo, Image, Machine};
+
+// Programs whose reference output we do not match yet
+const KNOWN_FAILURES: &[&str] = &[];
+
+fn tests_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("Tests")
+}
+
+// Each (name, input) case for one program, e.g. ("abs-01", Tests/input/abs-01.txt)
+fn cases(program: &str) -> Vec<(String, Option<PathBuf>)> {
+    let input_dir = tests_dir().join("input");
+    let mut cases = Vec::new();
+
+    let base = input_dir.join(format!("{}.txt", program));
+    if base.is_file() {
+        cases.push((program.to_string(), Some(base)));
+    }
+
+    let prefix = format!("{}-", program);
+    let mut numbered: Vec<PathBuf> = fs::read_dir(&input_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.file_name().unwrap().to_str().unwrap().starts_with(&prefix))
+        .collect();
+    numbered.sort();
+    for path in numbered {
+        let name = path.file_stem().unwrap().to_str().unwrap().to_string();
+        cases.push((name, Some(path)));
+    }
+
+    if cases.is_empty() {
+        cases.push((program.to_string(), None));
+    }
+    cases
+}
+
+fn run(image: &Image, input: Vec<u8>) -> Vec<u8> {
+    let mut m = Machine::new().with_io(BufferIo::new(input));
+    m.load_image(image).unwrap();
+    m.run();
+    m.into_io().into_output()
+}
+
+#[test]
+fn reference_outputs_match() {
+    let mut programs: Vec<PathBuf> = fs::read_dir(tests_dir().join("v"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "v"))
+        .collect();
+    programs.sort();
+
+    let mut failures = Vec::new();
+    let mut checked = 0;
+    for program in programs {
+        let stem = program.file_stem().unwrap().to_str().unwrap().to_string();
+        if KNOWN_FAILURES.contains(&stem.as_str()) {
+            continue;
+        }
+        let image = Image::from_file(&program).unwrap();
+
+        for (name, input) in cases(&stem) {
+            // No reference output means gradescript can't check it either
+            let expected = match fs::read(tests_dir().join("output").join(format!("{}.txt", name))) {
+                Ok(expected) => expected,
+                Err(_) => continue,
+            };
+            let input = input.map(|path| fs::read(path).unwrap()).unwrap_or_default();
+
+            checked += 1;
+            if run(&image, input) != expected {
+                failures.push(name);
+            }
+        }
+    }
+
+    assert!(checked > 0, "no reference tests found");
+    assert!(failures.is_empty(), "output differs for: {:?}", failures);
+}
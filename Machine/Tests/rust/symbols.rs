@@ -0,0 +1,113 @@
+// Computer Science 365: VM Project, Machine
+// Mike Hall, Seth Nelson, Sarah Pastor, Alan Saucer
+
+// Symbol files and everywhere a PC is shown with them
+
+mod common;
+
+use common::{assemble, machine};
+use machine::debugger::parse_address;
+use machine::disassembler::disassemble_with_symbols;
+use machine::{BufferIo, Debugger, ExitReason, Fault, Instruction, Machine, SymbolError, Symbols, VmError};
+
+const SYMBOLS: &str = "\
+# div.sym
+label main 0x0000
+label Divide 0x0008
+line 0x0000 div.asm 3
+line 0x0008 div.asm 6
+line 0x000c div.asm 7
+";
+
+#[test]
+fn parse_and_describe() {
+    let symbols = Symbols::parse(SYMBOLS).unwrap();
+    assert_eq!(symbols.address_of("Divide"), Some(8));
+    assert_eq!(symbols.address_of("Nowhere"), None);
+    assert_eq!(symbols.nearest_label(0x10), Some(("Divide", 8)));
+    assert_eq!(symbols.describe(0x0), Some("main div.asm:3".to_string()));
+    assert_eq!(symbols.describe(0x4), Some("main+4".to_string()));
+    assert_eq!(symbols.describe(0xc), Some("Divide+4 div.asm:7".to_string()));
+    assert_eq!(Symbols::parse(&symbols.to_text()).unwrap(), symbols);
+}
+
+#[test]
+fn bad_lines_are_reported() {
+    match Symbols::parse("label main 0\nlabel Menu\n") {
+        Err(SymbolError::Parse { line, text }) => {
+            assert_eq!(line, 2);
+            assert_eq!(text, "label Menu");
+        }
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn disassembly_shows_labels_and_lines() {
+    let code = assemble(&[
+        Instruction::Push { value: 1 },
+        Instruction::Goto { offset: 4 },
+        Instruction::Push { value: 0 },
+        Instruction::Div,
+    ]);
+    let text = disassemble_with_symbols(&code, Some(&Symbols::parse(SYMBOLS).unwrap()));
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines[0], "main:");
+    assert_eq!(lines[1], "0x0000: f0000001  push 1               # div.asm:3");
+    assert_eq!(lines[2], "0x0004: 70000004  goto 4               # 0x0008 Divide");
+    assert_eq!(lines[3], "Divide:");
+}
+
+#[test]
+fn machine_shows_addresses_with_symbols() {
+    let mut m = Machine::new().with_io(BufferIo::new(Vec::new()));
+    assert_eq!(m.describe_address(8), "0x0008");
+    m.set_symbols(Some(Symbols::parse(SYMBOLS).unwrap()));
+    assert_eq!(m.describe_address(8), "0x0008 <Divide div.asm:6>");
+    assert_eq!(parse_address("Divide", &m), Some(8));
+    assert_eq!(parse_address("0x10", &m), Some(16));
+}
+
+const NESTED: &str = "\
+label main 0x0000
+label Outer 0x0008
+label Inner 0x0010
+";
+
+// main calls Outer, Outer calls Inner, Inner stops at a debug and then divides by zero
+fn nested_calls() -> Machine<BufferIo> {
+    let mut m = machine(&[
+        Instruction::Call { offset: 8 },        // 0: main
+        Instruction::Exit { code: 0 },          // 4
+        Instruction::Call { offset: 8 },        // 8: Outer
+        Instruction::Return { offset: 0 },      // 12
+        Instruction::Debug { value: 1 },        // 16: Inner
+        Instruction::Push { value: 1 },         // 20
+        Instruction::Push { value: 0 },         // 24
+        Instruction::Div,                       // 28
+    ], "");
+    m.set_symbols(Some(Symbols::parse(NESTED).unwrap()));
+    m
+}
+
+const BACKTRACE: &str = concat!(
+    "  #0 0x0008 <Outer> called 0x0010 <Inner>\n",
+    "  #1 0x0000 <main> called 0x0008 <Outer>\n",
+);
+
+#[test]
+fn faults_have_a_backtrace() {
+    let mut m = nested_calls();
+    assert_eq!(m.backtrace(), "");
+    assert_eq!(m.run(), ExitReason::Fault(Fault { pc: 28, error: VmError::DivideByZero }));
+    assert_eq!(m.backtrace(), BACKTRACE);
+}
+
+#[test]
+fn debugger_shows_the_backtrace() {
+    let mut m = nested_calls();
+    let mut debugger = Debugger::with_console(BufferIo::new(b"bt\nc\n".to_vec()));
+    debugger.run(&mut m);
+    let shown = String::from_utf8(debugger.console().output().to_vec()).unwrap();
+    assert!(shown.contains(&format!("(debug) {}(debug) ", BACKTRACE)), "{}", shown);
+}
@@ -0,0 +1,95 @@
+// Computer Science 365: VM Project, Machine
+// Mike Hall, Seth Nelson, Sarah Pastor, Alan Saucer
+
+// What --trace writes, to a buffer and to a file
+
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+mod common;
+
+use common::machine;
+use machine::{ExitReason, Instruction, Symbols, Tracer};
+
+// A Write the test can still read after handing it to the Tracer
+#[derive(Clone, Default)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+const PROGRAM: [Instruction; 4] = [
+    Instruction::Push { value: 5 },
+    Instruction::Push { value: 3 },
+    Instruction::Sub,
+    Instruction::Exit { code: 2 },
+];
+
+const TRACE: &str = "\
+0x0000  f0000005  push 5              sp 0x1000 -> 0x0ffc  top [5]
+0x0004  f0000003  push 3              sp 0x0ffc -> 0x0ff8  top [3, 5]
+0x0008  21000000  sub                 sp 0x0ff8 -> 0x0ffc  top [2]
+0x000c  00000002  exit 2              sp 0x0ffc -> 0x0ffc  top [2]
+halted: exit instruction with code 2
+";
+
+#[test]
+fn trace_lines() {
+    let buffer = SharedBuffer::default();
+    let mut m = machine(&PROGRAM, "");
+    m.set_tracer(Some(Tracer::new(Box::new(buffer.clone()))));
+    assert_eq!(m.run(), ExitReason::Exit(2));
+    assert_eq!(String::from_utf8(buffer.0.borrow().clone()).unwrap(), TRACE);
+}
+
+// --trace=file
+#[test]
+fn trace_to_file() {
+    let path = std::env::temp_dir().join(format!("machine-trace-{}.txt", std::process::id()));
+    let mut m = machine(&PROGRAM, "");
+    m.set_tracer(Some(Tracer::to_file(&path).unwrap()));
+    m.run();
+    m.tracer_mut().unwrap().flush().unwrap();
+    let written = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(written, TRACE);
+}
+
+#[test]
+fn trace_shows_source_locations() {
+    let buffer = SharedBuffer::default();
+    let mut m = machine(&PROGRAM, "");
+    m.set_symbols(Some(Symbols::parse("label main 0\nline 0x8 sub.asm 4\n").unwrap()));
+    m.set_tracer(Some(Tracer::new(Box::new(buffer.clone()))));
+    m.run();
+    let trace = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+    let lines: Vec<&str> = trace.lines().collect();
+    assert_eq!(lines[0], "0x0000  f0000005  push 5              sp 0x1000 -> 0x0ffc  top [5]  <main>");
+    assert_eq!(lines[2], "0x0008  21000000  sub                 sp 0x0ff8 -> 0x0ffc  top [2]  <main+8 sub.asm:4>");
+}
+
+// The word shown is the one that ran, even when the instruction writes over it
+#[test]
+fn trace_shows_the_word_before_it_is_overwritten() {
+    let program = [
+        Instruction::Push { value: 7 },
+        Instruction::Swap { from: 0, to: -0x0ff8 },   // swaps the top with itself at 0x0004
+    ];
+    let buffer = SharedBuffer::default();
+    let mut m = machine(&program, "");
+    m.set_tracer(Some(Tracer::new(Box::new(buffer.clone()))));
+    m.run();
+    assert_eq!(m.peek(4), 7);
+    let trace = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+    assert_eq!(trace.lines().nth(1), Some("0x0004  01000c02  swap 0 -4088        sp 0x0ffc -> 0x0ffc  top [16780290]"));
+}
@@ -0,0 +1,469 @@
+// Computer Science 365: VM Project, Machine
+// Mike Hall, Seth Nelson, Sarah Pastor, Alan Saucer
+
+// Machine behaviour that the reference programs don't cover
+
+mod common;
+
+use common::{assemble, machine};
+use machine::{BufferIo, Cond, ExitReason, Fault, Frame, Instruction, LoadError, MAX_MEMORY_SIZE, Machine, MemorySizeError, PrintFormat, Step, UnaryCond, VmError, validate_memory_size};
+
+// Writing over the code region must not leave a stale pre-decoded instruction behind
+#[test]
+fn overwritten_code_is_decoded_again() {
+    let program = [
+        Instruction::Push { value: 7 },                // 0: the word for exit 7
+        Instruction::Swap { from: 0, to: 12 - 4092 },  // 4: write it over address 12
+        Instruction::Nop,                              // 8
+        Instruction::Exit { code: 1 },                 // 12
+    ];
+    assert_eq!(machine(&program, "").run(), ExitReason::Exit(7));
+}
+
+// The loaded length is the program, even when its last words encode to zero
+#[test]
+fn trailing_zero_words_are_part_of_the_program() {
+    let program = [
+        Instruction::Push { value: 0 },
+        Instruction::Exit { code: 0 },      // 0x00000000
+        Instruction::Exit { code: 0 },
+    ];
+    let mut m = machine(&program, "");
+    assert_eq!(m.run(), ExitReason::Exit(0));
+    assert_eq!(m.get_program_counter(), 4);
+    assert_eq!(m.get_stack_pointer(), 4096 - 4);
+}
+
+// Programs bigger than the default 4 KiB fit once the machine is given more memory
+#[test]
+fn memory_size_is_configurable() {
+    let mut program = vec![Instruction::Nop; 2000];
+    program.extend([Instruction::Push { value: 9 }, Instruction::Dump, Instruction::Exit { code: 5 }]);
+    let code = assemble(&program);
+
+    let mut small = Machine::new().with_io(BufferIo::new(Vec::new()));
+    assert!(matches!(small.load_bytes(&code), Err(LoadError::DoesNotFit { address: 0, len: 8012 })));
+
+    let mut m = Machine::with_memory(64 * 1024).with_io(BufferIo::new(Vec::new()));
+    m.load_bytes(&code).unwrap();
+    assert_eq!(m.memory_size(), 0x10000);
+    assert_eq!(m.run(), ExitReason::Exit(5));
+    assert_eq!(m.get_stack_pointer(), 0xfffc);
+    assert_eq!(m.peek(0xfffc), 9);
+    assert_eq!(m.io().output(), b"fffc: 00000009\n");
+}
+
+// Offsets only reach 28 bits, so RAM can't be any bigger than that
+#[test]
+#[should_panic(expected = "memory size must be at most 268435456 bytes")]
+fn memory_size_is_limited_to_28_bits() {
+    Machine::with_memory(MAX_MEMORY_SIZE + 4);
+}
+
+// The check --memory and Machine::with_memory share
+#[test]
+fn memory_sizes_are_validated() {
+    assert_eq!(validate_memory_size(4), Ok(4));
+    assert_eq!(validate_memory_size(MAX_MEMORY_SIZE), Ok(MAX_MEMORY_SIZE));
+    assert_eq!(validate_memory_size(0), Err(MemorySizeError::NotWords { bytes: 0 }));
+    assert_eq!(validate_memory_size(4098), Err(MemorySizeError::NotWords { bytes: 4098 }));
+    assert_eq!(validate_memory_size(MAX_MEMORY_SIZE + 4), Err(MemorySizeError::TooBig { bytes: MAX_MEMORY_SIZE + 4 }));
+}
+
+#[test]
+fn step_budget_stops_a_runaway_program() {
+    let mut m = machine(&[Instruction::Nop, Instruction::Goto { offset: -4 }], "");
+    m.set_max_steps(Some(101));
+    assert_eq!(m.run(), ExitReason::StepLimit { pc: 4, steps: 101 });
+    assert_eq!(m.get_steps(), 101);
+    assert_eq!(m.run().code(), 124);
+}
+
+#[test]
+fn repeated_state_is_an_infinite_loop() {
+    // Pushes and pops the same value forever, memory and SP keep coming back
+    let program = [
+        Instruction::Push { value: 1 },
+        Instruction::Push { value: 2 },
+        Instruction::Pop { offset: 4 },
+        Instruction::Goto { offset: -8 },
+    ];
+    let mut m = machine(&program, "");
+    m.set_loop_detection(true);
+    assert!(matches!(m.run(), ExitReason::InfiniteLoop { .. }));
+    assert!(m.get_steps() < 20);
+}
+
+#[test]
+fn changing_state_is_not_a_loop() {
+    // Count down from 1000, SP stays put but the counter changes every time
+    let program = [
+        Instruction::Push { value: 1000 },
+        Instruction::Push { value: 1 },
+        Instruction::Sub,
+        Instruction::UnaryIf { cond: UnaryCond::Nz, offset: -8 },
+        Instruction::Exit { code: 3 },
+    ];
+    let mut m = machine(&program, "");
+    m.set_loop_detection(true);
+    assert_eq!(m.run(), ExitReason::Exit(3));
+}
+
+#[test]
+fn input_resets_loop_detection() {
+    // Same state every time round, but each trip reads a new line
+    let program = [Instruction::Input, Instruction::Pop { offset: 4 }, Instruction::Goto { offset: -8 }];
+    let mut m = machine(&program, "1\n2\n3\n4\n5\n");
+    m.set_loop_detection(true);
+    m.set_max_steps(Some(300));
+    assert_eq!(m.run(), ExitReason::StepLimit { pc: 0, steps: 300 });
+}
+
+// Everything that reads or pops past the bottom of the stack, with the PC it happens at
+#[test]
+fn strict_mode_reports_stack_underflow() {
+    let underflows = [
+        vec![Instruction::Push { value: 1 }, Instruction::Pop { offset: 8 }],
+        vec![Instruction::Push { value: 1 }, Instruction::Dup { offset: 4 }],
+        vec![Instruction::Push { value: 1 }, Instruction::Print { offset: 4, format: PrintFormat::Decimal }],
+        vec![Instruction::Push { value: 1 }, Instruction::BinaryIf { cond: Cond::Eq, offset: 8 }],
+        vec![Instruction::Nop, Instruction::UnaryIf { cond: UnaryCond::Ez, offset: 8 }],
+        vec![Instruction::Push { value: 1 }, Instruction::Return { offset: 8 }],
+    ];
+    for program in underflows {
+        let mut m = machine(&program, "");
+        m.set_strict(true);
+        let sp = if program[0] == Instruction::Nop { 0x1000 } else { 0x0ffc };
+        let fault = Fault { pc: 4, error: VmError::StackUnderflow { sp } };
+        assert_eq!(m.run(), ExitReason::Fault(fault), "{:?}", program);
+    }
+}
+
+// Without strict mode the old behavior stays: reads past the base give 0, pop stops at the base
+#[test]
+fn compatibility_mode_clamps() {
+    let program = [
+        Instruction::Push { value: 1 },
+        Instruction::Pop { offset: 8 },
+        Instruction::Dup { offset: 4 },
+        Instruction::Print { offset: 0, format: PrintFormat::Decimal },
+    ];
+    let mut m = machine(&program, "");
+    assert_eq!(m.run(), ExitReason::EndOfProgram);
+    assert_eq!(m.get_stack_pointer(), 0x0ffc);
+    assert_eq!(m.io().output(), b"0\n");
+}
+
+// A program that keeps pushing until the stack reaches its own code
+fn stack_bomb() -> Vec<Instruction> {
+    vec![Instruction::Push { value: 7 }, Instruction::Goto { offset: -4 }]
+}
+
+#[test]
+fn stack_collision_names_the_pushing_instruction() {
+    let mut m = machine(&stack_bomb(), "");
+    let reason = m.run();
+    assert_eq!(reason, ExitReason::StackCollision { pc: 0, sp: 8 });
+    assert_eq!(reason.to_string(), "stack overflow into code at PC=0x0000, SP=0x0008");
+    assert_eq!(reason.code(), 1);
+}
+
+// The stack halts as a collision before a push can reach the code, but swap
+// can write anywhere
+#[test]
+fn protected_code_traps_writes() {
+    let program = [
+        Instruction::Push { value: 7 },
+        Instruction::Swap { from: 0, to: 12 - 4092 },
+        Instruction::Nop,
+        Instruction::Exit { code: 1 },
+    ];
+    let mut m = machine(&program, "");
+    m.set_code_protection(true);
+    let fault = Fault { pc: 4, error: VmError::CodeWrite { address: 12 } };
+    assert_eq!(m.run(), ExitReason::Fault(fault));
+    assert_eq!(m.peek(12), 1);  // still exit 1
+}
+
+#[test]
+fn calls_are_tracked_as_frames() {
+    let program = [
+        Instruction::Push { value: 5 },         // 0
+        Instruction::Call { offset: 12 },       // 4: to Outer
+        Instruction::Exit { code: 9 },          // 8
+        Instruction::Exit { code: 1 },          // 12
+        Instruction::Call { offset: 8 },        // 16: Outer, to Inner
+        Instruction::Return { offset: 0 },      // 20
+        Instruction::Return { offset: 0 },      // 24: Inner
+    ];
+    let outer = Frame { call_site: 4, entry: 16, return_address: 8, caller_sp: 0x0ffc };
+    let inner = Frame { call_site: 16, entry: 24, return_address: 20, caller_sp: 0x0ff8 };
+
+    let mut m = machine(&program, "");
+    m.step();
+    m.step();
+    assert_eq!(m.frames(), &[outer]);
+    m.step();
+    assert_eq!(m.frames(), &[outer, inner]);
+    m.step();
+    assert_eq!(m.frames(), &[outer]);
+    assert_eq!(m.run(), ExitReason::Exit(9));
+    assert!(m.frames().is_empty());
+}
+
+#[test]
+fn strict_return_must_match_the_innermost_call() {
+    let program = [
+        Instruction::Call { offset: 8 },        // 0
+        Instruction::Exit { code: 0 },          // 4
+        Instruction::Push { value: 5 },         // 8
+        Instruction::Return { offset: 0 },      // 12: pops the 5, not the return address
+    ];
+    let mut m = machine(&program, "");
+    m.set_strict(true);
+    let expected = Frame { call_site: 0, entry: 8, return_address: 4, caller_sp: 0x1000 };
+    let error = VmError::ReturnMismatch { return_address: 5, sp: 0x0ffc, expected: Some(expected) };
+    assert_eq!(m.run(), ExitReason::Fault(Fault { pc: 12, error }));
+    assert_eq!(
+        error.to_string(),
+        "return to 0x0005 (SP=0x0ffc) does not match the call at 0x0000, which returns to 0x0004 (SP=0x1000)"
+    );
+}
+
+#[test]
+fn strict_return_without_a_call_faults() {
+    let program = [Instruction::Push { value: 8 }, Instruction::Return { offset: 0 }, Instruction::Exit { code: 0 }];
+    let mut m = machine(&program, "");
+    m.set_strict(true);
+    let error = VmError::ReturnMismatch { return_address: 8, sp: 0x1000, expected: None };
+    assert_eq!(m.run(), ExitReason::Fault(Fault { pc: 4, error }));
+    assert_eq!(error.to_string(), "return to 0x0008 (SP=0x1000) without a call");
+}
+
+// Outside strict mode return is what the ISA says: pop an address and go there
+#[test]
+fn return_is_an_indirect_jump() {
+    let program = [
+        Instruction::Push { value: 12 },
+        Instruction::Return { offset: 0 },
+        Instruction::Exit { code: 1 },
+        Instruction::Exit { code: 2 },          // 12
+    ];
+    assert_eq!(machine(&program, "").run(), ExitReason::Exit(2));
+}
+
+// A callee that leaves with goto keeps its frame until an outer call returns
+#[test]
+fn frames_left_by_goto_are_unwound() {
+    let program = [
+        Instruction::Call { offset: 8 },        // 0
+        Instruction::Exit { code: 5 },          // 4
+        Instruction::Call { offset: 8 },        // 8: Outer
+        Instruction::Return { offset: 0 },      // 12
+        Instruction::Pop { offset: 4 },         // 16: Inner, drops its return address
+        Instruction::Goto { offset: -8 },       // 20: and jumps back into Outer
+    ];
+    let mut m = machine(&program, "");
+    for _ in 0..4 {
+        m.step();
+    }
+    assert_eq!(m.frames().len(), 2);
+    assert_eq!(m.run(), ExitReason::Exit(5));
+    assert!(m.frames().is_empty());
+}
+
+// Every level of a recursion returns to the same address, so the frame that
+// returns has to be picked by where its return address was on the stack
+#[test]
+fn recursion_left_by_goto_unwinds_the_right_frame() {
+    let program = [
+        Instruction::Push { value: 2 },                             // 0: n
+        Instruction::Call { offset: 8 },                            // 4: to F
+        Instruction::Exit { code: 0 },                              // 8
+        Instruction::Dup { offset: 4 },                             // 12: F, copy n
+        Instruction::UnaryIf { cond: UnaryCond::Ez, offset: 24 },   // 16: n == 0 escapes
+        Instruction::Push { value: 1 },                             // 20
+        Instruction::Sub,                                           // 24
+        Instruction::Call { offset: -16 },                          // 28: F(n - 1)
+        Instruction::Pop { offset: 4 },                             // 32
+        Instruction::Return { offset: 0 },                          // 36
+        Instruction::Pop { offset: 8 },                             // 40: drops the copy and its own return address
+        Instruction::Goto { offset: -12 },                          // 44: and carries on in its caller
+    ];
+    let mut m = machine(&program, "");
+    let mut after_returns = Vec::new();
+    loop {
+        let step = m.step();
+        if let Some(reason) = step.halted {
+            assert_eq!(reason, ExitReason::Exit(0));
+            break;
+        }
+        if let Some(Instruction::Return { .. }) = step.instruction {
+            after_returns.push(m.backtrace());
+        }
+    }
+    assert_eq!(after_returns, ["  #0 0x0004 called 0x000c\n", ""]);
+    assert!(m.frames().is_empty());
+}
+
+// Negative offsets reach the words just below SP, the way call.asm prints a
+// value it already popped
+#[test]
+fn negative_offsets_read_below_sp() {
+    let program = [
+        Instruction::Push { value: 1 },
+        Instruction::Push { value: 2 },
+        Instruction::Pop { offset: 8 },
+        Instruction::Print { offset: -4, format: PrintFormat::Decimal },   // 1
+        Instruction::Dup { offset: -8 },
+        Instruction::Print { offset: 0, format: PrintFormat::Decimal },    // 2
+        Instruction::Pop { offset: -4 },                                   // uncovers the first 2 again
+        Instruction::Print { offset: 0, format: PrintFormat::Decimal },    // 2
+        Instruction::Print { offset: 4, format: PrintFormat::Decimal },    // 2
+    ];
+    let mut m = machine(&program, "");
+    assert_eq!(m.run(), ExitReason::EndOfProgram);
+    assert_eq!(m.get_stack_pointer(), 0x0ff8);
+    assert_eq!(m.io().output(), b"1\n2\n2\n2\n");
+}
+
+#[test]
+fn offsets_below_address_zero_fault() {
+    let below = [
+        Instruction::Dup { offset: -0x2000 },
+        Instruction::Print { offset: -0x2000, format: PrintFormat::Hex },
+        Instruction::Pop { offset: -0x2000 },
+        Instruction::Return { offset: -0x2000 },
+    ];
+    for instruction in below {
+        let error = VmError::OutOfBounds { address: -0x1000 };
+        assert_eq!(machine(&[instruction], "").run(), ExitReason::Fault(Fault { pc: 0, error }), "{:?}", instruction);
+    }
+}
+
+// A fault stops the machine for good, running it again must not carry on past it
+#[test]
+fn faults_are_sticky() {
+    let program = [
+        Instruction::Push { value: 1 },
+        Instruction::Push { value: 0 },
+        Instruction::Div,
+        Instruction::Push { value: 42 },
+        Instruction::Print { offset: 0, format: PrintFormat::Decimal },
+        Instruction::Exit { code: 3 },
+    ];
+    let fault = ExitReason::Fault(Fault { pc: 8, error: VmError::DivideByZero });
+    let mut m = machine(&program, "");
+    assert_eq!(m.run(), fault);
+    assert_eq!(m.get_program_counter(), 8);
+    assert_eq!(m.run(), fault);
+    assert_eq!(m.halt_reason(), Some(fault));
+    assert_eq!(m.step().halted, Some(fault));
+    assert_eq!(m.io().output(), b"");
+}
+
+// Runaway recursion ends in a collision with every call still on the books
+#[test]
+fn recursion_collision_keeps_its_frames() {
+    let program = [Instruction::Push { value: 1 }, Instruction::Call { offset: -4 }];
+    let mut m = machine(&program, "");
+    assert_eq!(m.run(), ExitReason::StackCollision { pc: 4, sp: 8 });
+    assert_eq!(m.frames().len(), 511);
+    assert!(m.backtrace().starts_with("  #0 0x0004 called 0x0000\n  #1 0x0004 called 0x0000\n"));
+}
+
+// Every fault an instruction can raise, with the PC of the instruction that raised it
+#[test]
+fn faults_name_the_faulting_instruction() {
+    let faults = [
+        (vec![Instruction::Push { value: 1 }, Instruction::Push { value: 0 }, Instruction::Div], 8, VmError::DivideByZero),
+        (vec![Instruction::Push { value: 1 }, Instruction::Pop { offset: 2 }, Instruction::Push { value: 1 }], 8, VmError::MisalignedStack { sp: 0x0ffe }),
+        (vec![Instruction::Push { value: 1 }, Instruction::Swap { from: 0, to: -8192 }], 4, VmError::OutOfBounds { address: -0x1004 }),
+        (vec![Instruction::StPrint { offset: -8192 }], 0, VmError::OutOfBounds { address: -0x1000 }),
+        (vec![Instruction::Nop, Instruction::Goto { offset: -8 }], 4, VmError::OutOfBounds { address: -4 }),
+        (vec![Instruction::Call { offset: -8 }], 0, VmError::OutOfBounds { address: -8 }),
+        (vec![Instruction::Push { value: 0 }, Instruction::UnaryIf { cond: UnaryCond::Ez, offset: -8 }], 4, VmError::OutOfBounds { address: -4 }),
+        (
+            vec![Instruction::Push { value: 1 }, Instruction::Dup { offset: 0 }, Instruction::BinaryIf { cond: Cond::Eq, offset: -12 }],
+            8,
+            VmError::OutOfBounds { address: -4 },
+        ),
+    ];
+    for (program, pc, error) in faults {
+        assert_eq!(machine(&program, "").run(), ExitReason::Fault(Fault { pc, error }), "{:?}", program);
+    }
+}
+
+// Binary if conditions 6 and 7 don't exist
+#[test]
+fn undecodable_words_are_illegal_instructions() {
+    let mut code = assemble(&[Instruction::Nop]);
+    code.extend(0x8c00_0000u32.to_le_bytes());
+    let mut m = Machine::new().with_io(BufferIo::new(Vec::new()));
+    m.load_bytes(&code).unwrap();
+    let error = VmError::IllegalInstruction { word: 0x8c00_0000 };
+    assert_eq!(m.run(), ExitReason::Fault(Fault { pc: 4, error }));
+}
+
+// run() stops with a StackCollision before a push can get this low, so this
+// goes through the stack API directly
+#[test]
+fn pushing_below_address_zero_overflows() {
+    let mut m = Machine::new().with_io(BufferIo::new(Vec::new()));
+    m.sp_jump(0);
+    assert_eq!(m.stack_push(1), Err(VmError::StackOverflow { sp: 0 }));
+    assert_eq!(m.get_stack_pointer(), 0);
+}
+
+// run() hands back why it stopped, and main turns that into the process status
+#[test]
+fn exit_reasons_map_to_exit_codes() {
+    let cases = [
+        (vec![Instruction::Exit { code: 123 }], ExitReason::Exit(123), 123),
+        (vec![Instruction::Exit { code: 255 }], ExitReason::Exit(255), 255),
+        (vec![Instruction::Push { value: 1 }, Instruction::Nop], ExitReason::EndOfProgram, 0),
+        (stack_bomb(), ExitReason::StackCollision { pc: 0, sp: 8 }, 1),
+        (
+            vec![Instruction::Push { value: 0 }, Instruction::Push { value: 0 }, Instruction::Rem],
+            ExitReason::Fault(Fault { pc: 8, error: VmError::DivideByZero }),
+            1,
+        ),
+    ];
+    for (program, reason, code) in cases {
+        assert_eq!(machine(&program, "").run(), reason, "{:?}", program);
+        assert_eq!(reason.code(), code, "{}", reason);
+    }
+    assert_eq!(ExitReason::Quit.code(), 0);
+    assert_eq!(ExitReason::StepLimit { pc: 0, steps: 10 }.code(), 124);
+    assert_eq!(ExitReason::InfiniteLoop { pc: 0 }.code(), 124);
+}
+
+// step() runs exactly one instruction and reports where that left the machine
+#[test]
+fn step_reports_each_instruction() {
+    let program = [Instruction::Push { value: 3 }, Instruction::Goto { offset: 8 }, Instruction::Nop, Instruction::Exit { code: 6 }];
+    let mut m = machine(&program, "");
+    let step = |pc, instruction, program_counter, stack_pointer, halted| Step {
+        pc,
+        instruction: Some(instruction),
+        program_counter,
+        stack_pointer,
+        halted,
+    };
+    assert_eq!(m.step(), step(0, Instruction::Push { value: 3 }, 4, 0x0ffc, None));
+    assert_eq!(m.step(), step(4, Instruction::Goto { offset: 8 }, 12, 0x0ffc, None));
+    assert_eq!(m.step(), step(12, Instruction::Exit { code: 6 }, 12, 0x0ffc, Some(ExitReason::Exit(6))));
+    assert_eq!(m.get_steps(), 3);
+}
+
+// Once the program has ended, step() executes nothing and says why
+#[test]
+fn step_after_the_end_executes_nothing() {
+    let mut m = machine(&[Instruction::Nop], "");
+    assert_eq!(m.step().halted, None);
+    let step = m.step();
+    assert_eq!(step.instruction, None);
+    assert_eq!(step.halted, Some(ExitReason::EndOfProgram));
+    assert_eq!((step.pc, step.program_counter, step.stack_pointer), (4, 4, 0x1000));
+    assert_eq!(m.get_steps(), 1);
+}
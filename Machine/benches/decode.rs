@@ -0,0 +1,94 @@
+// Computer Science 365: VM Project, Machine
+// Mike Hall, Seth Nelson, Sarah Pastor, Alan Saucer
+
+// Machine::run with its pre-decoded code against fetching and decoding
+// every word on every step, the way the machine used to run.
+// cargo bench
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use machine::{BufferIo, ExitReason, Instruction, Machine, Step, UnaryCond};
+
+const ITERATIONS: i32 = 2_000_000;
+
+// Count down from ITERATIONS, three instructions per trip round the loop
+fn countdown() -> Vec<u8> {
+    [
+        Instruction::Push { value: ITERATIONS },
+        Instruction::Push { value: 1 },
+        Instruction::Sub,
+        Instruction::UnaryIf { cond: UnaryCond::Nz, offset: -8 },
+        Instruction::Exit { code: 0 },
+    ]
+    .iter()
+    .flat_map(|instruction| instruction.encode())
+    .collect()
+}
+
+fn machine(code: &[u8]) -> Machine<BufferIo> {
+    let mut m = Machine::new().with_io(BufferIo::new(Vec::new()));
+    m.load_bytes(code).unwrap();
+    m
+}
+
+fn cached(code: &[u8]) {
+    machine(code).run();
+}
+
+// What Machine::step did before the code was pre-decoded: fetch the four
+// bytes at PC and decode them, on every single step
+fn decode_every_step(code: &[u8]) {
+    let mut m = machine(code);
+    loop {
+        let pc = m.get_program_counter();
+        let mut step = Step {
+            pc,
+            instruction: None,
+            program_counter: pc,
+            stack_pointer: m.get_stack_pointer(),
+            halted: m.halt_reason(),
+        };
+        if step.halted.is_none() {
+            let bytes = [*m.get_byte(pc), *m.get_byte(pc + 1), *m.get_byte(pc + 2), *m.get_byte(pc + 3)];
+            let instruction = Instruction::decode_instruction(&bytes).unwrap();
+            match instruction {
+                Instruction::Exit { code } => step.halted = Some(ExitReason::Exit(code as i32)),
+                Instruction::UnaryIf { .. } => instruction.execute(&mut m).unwrap(),
+                _ => {
+                    instruction.execute(&mut m).unwrap();
+                    m.pc_increment();
+                }
+            }
+            step.instruction = Some(instruction);
+            step.program_counter = m.get_program_counter();
+            step.stack_pointer = m.get_stack_pointer();
+        }
+        if black_box(step).halted.is_some() {
+            break;
+        }
+    }
+}
+
+// Best of a few runs
+fn time(run: fn(&[u8]), code: &[u8]) -> Duration {
+    (0..5)
+        .map(|_| {
+            let start = Instant::now();
+            run(code);
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let code = countdown();
+    let steps = ITERATIONS as f64 * 3.0;
+    let before = time(decode_every_step, &code);
+    let after = time(cached, &code);
+    for (name, elapsed) in [("decode every step", before), ("pre-decoded", after)] {
+        println!("{:<20}{:>10.2?}  {:>6.1} ns/step", name, elapsed, elapsed.as_nanos() as f64 / steps);
+    }
+    println!("speedup             {:>9.2}x", before.as_secs_f64() / after.as_secs_f64());
+}
@@ -0,0 +1,75 @@
+// Computer Science 365: VM Project, Machine
+// Mike Hall, Seth Nelson, Sarah Pastor, Alan Saucer
+
+// What add, sub, mul, div and neg do when the result doesn't fit in 32 bits
+// ex: 0x7fffffff + 1, or i32::MIN / -1
+
+use std::fmt;
+
+use crate::error::VmError;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ArithmeticMode {
+    #[default]
+    Wrapping,       // Two's complement wrap around, what real hardware does
+    Checked,        // Overflow is a fault
+    Saturating,     // Clamp to i32::MIN or i32::MAX
+}
+
+impl ArithmeticMode {
+    // `wrapping`, `checked` or `saturating`
+    pub fn parse(text: &str) -> Option<ArithmeticMode> {
+        match text {
+            "wrapping" => Some(ArithmeticMode::Wrapping),
+            "checked" => Some(ArithmeticMode::Checked),
+            "saturating" => Some(ArithmeticMode::Saturating),
+            _ => None,
+        }
+    }
+
+    pub fn add(self, left: i32, right: i32) -> Result<i32, VmError> {
+        self.apply(left.checked_add(right), left.wrapping_add(right), left.saturating_add(right))
+    }
+
+    pub fn sub(self, left: i32, right: i32) -> Result<i32, VmError> {
+        self.apply(left.checked_sub(right), left.wrapping_sub(right), left.saturating_sub(right))
+    }
+
+    pub fn mul(self, left: i32, right: i32) -> Result<i32, VmError> {
+        self.apply(left.checked_mul(right), left.wrapping_mul(right), left.saturating_mul(right))
+    }
+
+    // right must not be 0, that is a DivideByZero whatever the mode.
+    // i32::MIN / -1 is the only division that overflows.
+    pub fn div(self, left: i32, right: i32) -> Result<i32, VmError> {
+        self.apply(left.checked_div(right), left.wrapping_div(right), left.saturating_div(right))
+    }
+
+    // right must not be 0. i32::MIN % -1 is 0 in every mode, the result fits
+    // even though computing it the obvious way overflows.
+    pub fn rem(self, left: i32, right: i32) -> Result<i32, VmError> {
+        Ok(left.wrapping_rem(right))
+    }
+
+    pub fn neg(self, value: i32) -> Result<i32, VmError> {
+        self.apply(value.checked_neg(), value.wrapping_neg(), value.saturating_neg())
+    }
+
+    fn apply(self, checked: Option<i32>, wrapping: i32, saturating: i32) -> Result<i32, VmError> {
+        match self {
+            ArithmeticMode::Wrapping => Ok(wrapping),
+            ArithmeticMode::Checked => checked.ok_or(VmError::Overflow),
+            ArithmeticMode::Saturating => Ok(saturating),
+        }
+    }
+}
+
+impl fmt::Display for ArithmeticMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArithmeticMode::Wrapping => write!(f, "wrapping"),
+            ArithmeticMode::Checked => write!(f, "checked"),
+            ArithmeticMode::Saturating => write!(f, "saturating"),
+        }
+    }
+}
@@ -0,0 +1,422 @@
+// Computer Science 365: VM Project, Machine
+// Mike Hall, Seth Nelson, Sarah Pastor, Alan Saucer
+
+// Interactive debugger
+// Runs the machine one step at a time and drops into a prompt whenever a
+// `debug` instruction executes, a breakpoint is reached, a watched word
+// changes, or after every step while stepping.
+
+use std::fmt;
+use std::io;
+use std::io::Write;
+
+use crate::helper::parse_number;
+use crate::instruction::Instruction;
+use crate::io::MachineIo;
+use crate::machine::{ExitReason, Machine};
+
+// How many stack slots the prompt shows
+const STACK_WORDS: usize = 8;
+
+const HELP: &str = "\
+commands:
+  s, step [n]         execute n instructions (default 1)
+  c, continue         run until the next debug instruction, breakpoint or watchpoint
+  x, mem <addr> [n]   dump n words of memory (default 4), addr may be sp+N, pc or a label
+  p, print <addr>     show the word at addr in decimal and hex
+  stack               show the top of the stack
+  bt, backtrace       show the calls that haven't returned yet
+  r, regs             show PC and SP
+  b, break <addr>     stop before executing the instruction at addr (or label)
+  w, watch <target>   stop when the word at target changes (addr, or sp+N to follow SP)
+  info                list breakpoints and watchpoints
+  clear               remove all breakpoints and watchpoints
+  q, quit             stop the machine
+  h, help             show this message
+";
+
+// Debugger prompt talks to the terminal: commands from stdin, everything else
+// on stderr so it never mixes with the program's own output
+#[derive(Debug, Default)]
+pub struct DebugConsole;
+
+impl MachineIo for DebugConsole {
+    fn read_line(&mut self, buf: &mut String) -> io::Result<usize> {
+        io::stdin().read_line(buf)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        io::stderr().write_all(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+// What a watchpoint keeps an eye on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchTarget {
+    Address(usize),     // A fixed RAM address
+    Stack(i64),         // The slot at SP + offset, wherever SP currently is
+}
+
+impl WatchTarget {
+    // `sp`, `sp+N`, `sp-N` or a plain address
+    pub fn parse(text: &str) -> Option<WatchTarget> {
+        let text = text.trim();
+        match text.strip_prefix("sp") {
+            Some("") => Some(WatchTarget::Stack(0)),
+            Some(offset) => {
+                let offset = offset.strip_prefix('+').unwrap_or(offset);
+                Some(WatchTarget::Stack(parse_number(offset)?))
+            }
+            None => {
+                let address = parse_number(text)?;
+                if address < 0 {
+                    return None;
+                }
+                Some(WatchTarget::Address(address as usize))
+            }
+        }
+    }
+
+    // Address currently watched, None if it falls outside of RAM
+    pub fn resolve<I: MachineIo>(&self, machine: &Machine<I>) -> Option<usize> {
+        let address = match *self {
+            WatchTarget::Address(address) => address as i64,
+            WatchTarget::Stack(offset) => machine.get_stack_pointer() as i64 + offset,
+        };
+        if address < 0 || address as usize + 4 > machine.memory_size() {
+            return None;
+        }
+        Some(address as usize)
+    }
+
+    // The word being watched, None if it is outside of RAM
+    fn read<I: MachineIo>(&self, machine: &Machine<I>) -> Option<i32> {
+        self.resolve(machine).map(|address| machine.peek(address))
+    }
+}
+
+impl fmt::Display for WatchTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WatchTarget::Address(address) => write!(f, "0x{:04x}", address),
+            WatchTarget::Stack(offset) if *offset < 0 => write!(f, "sp{}", offset),
+            WatchTarget::Stack(offset) => write!(f, "sp+{}", offset),
+        }
+    }
+}
+
+struct Watchpoint {
+    target: WatchTarget,
+    last: Option<i32>,  // Value seen after the previous step
+}
+
+pub struct Debugger<C: MachineIo = DebugConsole> {
+    console: C,
+    steps_left: u64,            // Instructions to run before prompting again, 0 = until the next debug
+    breakpoints: Vec<usize>,
+    watchpoints: Vec<Watchpoint>,
+    resuming: bool,             // Just prompted at this PC, don't stop at its breakpoint again
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self::with_console(DebugConsole)
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: MachineIo> Debugger<C> {
+    pub fn with_console(console: C) -> Self {
+        Self {
+            console,
+            steps_left: 0,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            resuming: false,
+        }
+    }
+
+    // Stop before the instruction at pc executes
+    pub fn add_breakpoint(&mut self, pc: usize) {
+        if !self.breakpoints.contains(&pc) {
+            self.breakpoints.push(pc);
+        }
+    }
+
+    // Stop after any step that changes the watched word
+    pub fn add_watchpoint(&mut self, target: WatchTarget) {
+        self.watchpoints.push(Watchpoint { target, last: None });
+    }
+
+    pub fn breakpoints(&self) -> &[usize] {
+        &self.breakpoints
+    }
+
+    pub fn watchpoints(&self) -> Vec<WatchTarget> {
+        self.watchpoints.iter().map(|watch| watch.target).collect()
+    }
+
+    pub fn console(&self) -> &C {
+        &self.console
+    }
+
+    // Run the machine to completion, stopping at debug instructions,
+    // breakpoints and watchpoints
+    pub fn run<I: MachineIo>(&mut self, machine: &mut Machine<I>) -> ExitReason {
+        self.refresh_watchpoints(machine);
+        loop {
+            let pc = machine.get_program_counter();
+            if !self.resuming && self.breakpoints.contains(&pc) {
+                self.steps_left = 0;
+                self.say(&format!("breakpoint at PC={}\n", machine.describe_address(pc)));
+                if !self.prompt(machine) {
+                    return ExitReason::Quit;
+                }
+                continue;
+            }
+
+            let step = machine.step();
+            self.resuming = false;
+            if let Some(reason) = step.halted {
+                return reason;
+            }
+
+            if let Some(changes) = self.check_watchpoints(machine) {
+                self.steps_left = 0;
+                self.say(&format!("{} at PC={}\n", step.instruction.unwrap(), machine.describe_address(step.pc)));
+                self.say(&changes);
+            } else if let Some(instruction @ Instruction::Debug { .. }) = step.instruction {
+                self.steps_left = 0;
+                self.say(&format!("{} at PC={}\n", instruction, machine.describe_address(step.pc)));
+            } else if self.steps_left > 0 {
+                self.steps_left -= 1;
+                if self.steps_left > 0 {
+                    continue;
+                }
+                self.say(&format!("stepped {} at PC={}\n", step.instruction.unwrap(), machine.describe_address(step.pc)));
+            } else {
+                continue;
+            }
+
+            if !self.prompt(machine) {
+                return ExitReason::Quit;
+            }
+        }
+    }
+
+    // Read and run commands until one resumes execution.
+    // Returns false if the user asked to quit.
+    fn prompt<I: MachineIo>(&mut self, machine: &Machine<I>) -> bool {
+        self.resuming = true;
+        self.show_registers(machine);
+        self.show_stack(machine);
+        loop {
+            self.say("(debug) ");
+            let mut line = String::new();
+            match self.console.read_line(&mut line) {
+                Ok(0) | Err(_) => return true, // Nobody is there to answer, keep going
+                Ok(_) => {}
+            }
+
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                [] | ["s"] | ["step"] => {
+                    self.steps_left = 1;
+                    return true;
+                }
+                ["s", n] | ["step", n] => match parse_number(n) {
+                    Some(n) if n > 0 => {
+                        self.steps_left = n as u64;
+                        return true;
+                    }
+                    _ => self.say("step count must be a positive number\n"),
+                },
+                ["c"] | ["continue"] => return true,
+                ["q"] | ["quit"] => return false,
+                ["r"] | ["regs"] => self.show_registers(machine),
+                ["stack"] => self.show_stack(machine),
+                ["bt"] | ["backtrace"] => self.show_backtrace(machine),
+                ["x", rest @ ..] | ["mem", rest @ ..] => self.dump_memory(machine, rest),
+                ["p", address] | ["print", address] => self.print_word(machine, address),
+                ["b", address] | ["break", address] => match parse_address(address, machine) {
+                    Some(pc) => {
+                        self.add_breakpoint(pc);
+                        self.say(&format!("breakpoint at {}\n", machine.describe_address(pc)));
+                    }
+                    None => self.say("bad address\n"),
+                },
+                ["w", target] | ["watch", target] => match WatchTarget::parse(target) {
+                    Some(target) => {
+                        let last = target.read(machine);
+                        self.watchpoints.push(Watchpoint { target, last });
+                        self.say(&format!("watching {}\n", target));
+                    }
+                    None => self.say("bad watch target\n"),
+                },
+                ["info"] => self.show_points(machine),
+                ["clear"] => {
+                    self.breakpoints.clear();
+                    self.watchpoints.clear();
+                }
+                ["h"] | ["help"] => self.say(HELP),
+                _ => self.say("unknown command, try 'help'\n"),
+            }
+        }
+    }
+
+    // Remember the current value of every watched word
+    fn refresh_watchpoints<I: MachineIo>(&mut self, machine: &Machine<I>) {
+        for watch in &mut self.watchpoints {
+            watch.last = watch.target.read(machine);
+        }
+    }
+
+    // Describe every watched word that changed since the last step, if any did
+    fn check_watchpoints<I: MachineIo>(&mut self, machine: &Machine<I>) -> Option<String> {
+        let mut changes = String::new();
+        for watch in &mut self.watchpoints {
+            let value = watch.target.read(machine);
+            if value != watch.last {
+                changes.push_str(&format!(
+                    "watchpoint {}: {} -> {}\n",
+                    watch.target,
+                    show_word(watch.last),
+                    show_word(value)
+                ));
+                watch.last = value;
+            }
+        }
+        if changes.is_empty() { None } else { Some(changes) }
+    }
+
+    fn show_points<I: MachineIo>(&mut self, machine: &Machine<I>) {
+        let mut text = String::new();
+        for pc in &self.breakpoints {
+            text.push_str(&format!("  break {}\n", machine.describe_address(*pc)));
+        }
+        for watch in &self.watchpoints {
+            text.push_str(&format!("  watch {} = {}\n", watch.target, show_word(watch.last)));
+        }
+        if text.is_empty() {
+            text.push_str("no breakpoints or watchpoints\n");
+        }
+        self.say(&text);
+    }
+
+    fn show_registers<I: MachineIo>(&mut self, machine: &Machine<I>) {
+        self.say(&format!(
+            "PC={} SP=0x{:04x}\n",
+            machine.describe_address(machine.get_program_counter()),
+            machine.get_stack_pointer()
+        ));
+    }
+
+    fn show_stack<I: MachineIo>(&mut self, machine: &Machine<I>) {
+        let sp = machine.get_stack_pointer();
+        let bottom = machine.memory_size();
+        if sp >= bottom {
+            self.say("stack is empty\n");
+            return;
+        }
+        let mut text = String::new();
+        for address in (sp..bottom).step_by(4).take(STACK_WORDS) {
+            text.push_str(&format!("  sp+{:<4} {:04x}: {:08x}\n", address - sp, address, machine.peek(address)));
+        }
+        let depth = (bottom - sp) / 4;
+        if depth > STACK_WORDS {
+            text.push_str(&format!("  ... {} more\n", depth - STACK_WORDS));
+        }
+        self.say(&text);
+    }
+
+    fn show_backtrace<I: MachineIo>(&mut self, machine: &Machine<I>) {
+        match machine.backtrace() {
+            text if text.is_empty() => self.say("no calls in progress\n"),
+            text => self.say(&text),
+        }
+    }
+
+    fn print_word<I: MachineIo>(&mut self, machine: &Machine<I>, address: &str) {
+        match parse_address(address, machine) {
+            Some(address) if address + 4 <= machine.memory_size() => {
+                let value = machine.peek(address);
+                self.say(&format!("  {:04x}: {} (0x{:08x})\n", address, value, value));
+            }
+            _ => self.say("bad address\n"),
+        }
+    }
+
+    fn dump_memory<I: MachineIo>(&mut self, machine: &Machine<I>, args: &[&str]) {
+        let (start, count) = match args {
+            [address] => (parse_address(address, machine), Some(4)),
+            [address, count] => (parse_address(address, machine), parse_number(count)),
+            _ => {
+                self.say("usage: x <addr> [n]\n");
+                return;
+            }
+        };
+        let (start, count) = match (start, count) {
+            (Some(start), Some(count)) if count > 0 => (start, count as usize),
+            _ => {
+                self.say("bad address or count\n");
+                return;
+            }
+        };
+
+        let mut text = String::new();
+        for address in (start..).step_by(4).take(count) {
+            if address + 4 > machine.memory_size() {
+                break;
+            }
+            text.push_str(&format!("  {:04x}: {:08x}\n", address, machine.peek(address)));
+        }
+        self.say(&text);
+    }
+
+    fn say(&mut self, text: &str) {
+        let _ = self.console.write(text.as_bytes());
+        let _ = self.console.flush();
+    }
+}
+
+fn show_word(value: Option<i32>) -> String {
+    match value {
+        Some(value) => format!("{:08x}", value),
+        None => "--------".to_string(),
+    }
+}
+
+// A memory address: a number, `pc`, `sp`, `sp+N`/`sp-N` relative to the current SP,
+// or a label when the machine has symbols
+pub fn parse_address<I: MachineIo>(text: &str, machine: &Machine<I>) -> Option<usize> {
+    let text = text.trim();
+    let address = if text == "pc" {
+        machine.get_program_counter() as i64
+    } else if let Some(offset) = text.strip_prefix("sp") {
+        let sp = machine.get_stack_pointer() as i64;
+        if offset.is_empty() {
+            sp
+        } else if let Some(n) = offset.strip_prefix('+') {
+            sp + parse_number(n)?
+        } else {
+            sp + parse_number(offset)?
+        }
+    } else if let Some(address) = machine.symbols().and_then(|symbols| symbols.address_of(text)) {
+        address as i64
+    } else {
+        parse_number(text)?
+    };
+    if address < 0 || address as usize >= machine.memory_size() {
+        return None;
+    }
+    Some(address as usize)
+}
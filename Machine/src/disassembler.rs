@@ -0,0 +1,73 @@
+// Computer Science 365: VM Project, Machine
+// Mike Hall, Seth Nelson, Sarah Pastor, Alan Saucer
+
+// Disassembler for .v images
+// ex: 0x0000: 70000014  goto 20              # 0x0014
+// With symbols, labels get a line of their own and comments name the
+// branch target and the source line:
+//     Menu:
+//     0x0014: d0000000  print 0              # calc.asm:13
+
+use crate::instruction::Instruction;
+use crate::symbols::Symbols;
+
+// One line per word: address, raw hex, then the instruction in .asm syntax
+pub fn disassemble(code: &[u8]) -> String {
+    disassemble_with_symbols(code, None)
+}
+
+pub fn disassemble_with_symbols(code: &[u8], symbols: Option<&Symbols>) -> String {
+    let mut text = String::new();
+    for (index, chunk) in code.chunks(4).enumerate() {
+        let address = index * 4;
+        if let Some(symbols) = symbols {
+            for label in symbols.labels_at(address) {
+                text.push_str(&format!("{}:\n", label));
+            }
+        }
+        text.push_str(&disassemble_word_with_symbols(address, chunk, symbols));
+        text.push('\n');
+    }
+    text
+}
+
+pub fn disassemble_word(address: usize, bytes: &[u8]) -> String {
+    disassemble_word_with_symbols(address, bytes, None)
+}
+
+pub fn disassemble_word_with_symbols(address: usize, bytes: &[u8], symbols: Option<&Symbols>) -> String {
+    // A trailing partial word is padded with zeroes, like it would be in RAM
+    let mut word = [0u8; 4];
+    word[..bytes.len()].copy_from_slice(bytes);
+
+    let raw = u32::from_le_bytes(word);
+    let (text, mut comment) = match Instruction::decode_instruction(&word) {
+        Some(instruction) => {
+            let comment = match instruction.branch_offset() {
+                Some(offset) => {
+                    let target = address as i64 + offset as i64;
+                    let label = usize::try_from(target)
+                        .ok()
+                        .and_then(|target| symbols?.labels_at(target).first());
+                    match label {
+                        Some(label) => vec![format!("0x{:04x} {}", target, label)],
+                        None => vec![format!("0x{:04x}", target)],
+                    }
+                }
+                None => Vec::new(),
+            };
+            (instruction.to_string(), comment)
+        }
+        None => (format!(".word 0x{:08x}", raw), Vec::new()),
+    };
+    if let Some(line) = symbols.and_then(|symbols| symbols.line_at(address)) {
+        comment.push(line.to_string());
+    }
+
+    let line = if comment.is_empty() {
+        text
+    } else {
+        format!("{:<20} # {}", text, comment.join("  "))
+    };
+    format!("0x{:04x}: {:08x}  {}", address, raw, line)
+}
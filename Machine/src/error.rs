@@ -0,0 +1,63 @@
+// Computer Science 365: VM Project, Machine
+// Mike Hall, Seth Nelson, Sarah Pastor, Alan Saucer
+
+// Faults raised while executing instructions
+
+use std::fmt;
+
+use crate::machine::Frame;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    DivideByZero,
+    Overflow,                               // Result doesn't fit in 32 bits, in checked arithmetic mode
+    StackUnderflow { sp: usize },           // Tried to pop/read below the bottom of the stack
+    StackOverflow { sp: usize },            // Tried to push past the top of memory
+    OutOfBounds { address: i64 },           // Memory access outside of RAM
+    IllegalInstruction { word: u32 },       // Word does not decode to an instruction
+    MisalignedStack { sp: usize },          // Stack pointer is not a multiple of four
+    CodeWrite { address: usize },           // Write into the code region while it is read-only
+    ReturnMismatch { return_address: usize, sp: usize, expected: Option<Frame> }, // Return that doesn't undo the innermost call
+    Io(std::io::ErrorKind),                 // Console input or output failed
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::DivideByZero => write!(f, "divide by zero"),
+            VmError::Overflow => write!(f, "arithmetic overflow"),
+            VmError::StackUnderflow { sp } => write!(f, "stack underflow (SP=0x{:04x})", sp),
+            VmError::StackOverflow { sp } => write!(f, "stack overflow (SP=0x{:04x})", sp),
+            VmError::OutOfBounds { address } => write!(f, "out-of-bounds access at {}", address),
+            VmError::IllegalInstruction { word } => write!(f, "illegal instruction 0x{:08x}", word),
+            VmError::MisalignedStack { sp } => write!(f, "misaligned stack (SP=0x{:04x})", sp),
+            VmError::CodeWrite { address } => write!(f, "write to read-only code at 0x{:04x}", address),
+            VmError::ReturnMismatch { return_address, sp, expected: None } => write!(
+                f,
+                "return to 0x{:04x} (SP=0x{:04x}) without a call",
+                return_address, sp
+            ),
+            VmError::ReturnMismatch { return_address, sp, expected: Some(frame) } => write!(
+                f,
+                "return to 0x{:04x} (SP=0x{:04x}) does not match the call at 0x{:04x}, which returns to 0x{:04x} (SP=0x{:04x})",
+                return_address, sp, frame.call_site, frame.return_address, frame.caller_sp
+            ),
+            VmError::Io(kind) => write!(f, "I/O error: {}", kind),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+// A VmError together with the program counter of the instruction that raised it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    pub pc: usize,
+    pub error: VmError,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at PC=0x{:04x}", self.error, self.pc)
+    }
+}
@@ -10,4 +10,18 @@
 //       hex_string.push_str(&format!("{:02x} ", byte));
 //   }
 //   hex_string.trim_end().to_string()
-// }
\ No newline at end of file
+// }
+
+// Decimal or 0x-prefixed hex, optionally negative
+pub fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()?
+    } else {
+        digits.parse::<i64>().ok()?
+    };
+    Some(if negative { -value } else { value })
+}
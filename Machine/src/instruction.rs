@@ -2,22 +2,56 @@
 // Mike Hall, Seth Nelson, Sarah Pastor, Alan Saucer
 
 // Instructions
-use std::io::{stdin, Write};
-use std::u32;
-use std::convert::TryInto;
+use std::fmt;
 
-//#[derive(Debug, Clone)]
+use crate::error::VmError;
+use crate::io::MachineIo;
+use crate::machine::{Frame, Machine};
+
+// Binary if condition, bits 27:25 of the word
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cond {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+// Unary if condition, bits 26:25 of the word
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryCond {
+    Ez,
+    Nz,
+    Mi,
+    Pl,
+}
+
+// Print format, bits 1:0 of the word
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrintFormat {
+    Decimal,
+    Hex,
+    Binary,
+    Octal,
+}
+
+// Every operand is extracted (and sign-extended where the ISA says so) by
+// decode_instruction, so nothing downstream has to pick bits out of the word.
+// Offsets are in bytes.
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[allow(dead_code)] // call me a tattletale, this is some AI shit Alan probably put here to silence the errors.
 pub enum Instruction {
     // OPCODE 0: Miscellaneous
-    Exit,
-    Swap([u8; 4]),
+    Exit { code: u8 },
+    Swap { from: i32, to: i32 },
     Nop,
     Input,
-    StInput([u8; 4]),
-    Debug,
+    StInput { max_chars: u32 },
+    Debug { value: u32 },
     // OPCODE 1: Pop
-    Pop(u32),
+    Pop { offset: i32 },
     // OPCODE 2: Binary arithmetic
     Add,
     Sub,
@@ -34,22 +68,25 @@ pub enum Instruction {
     Neg,
     Not,
     // OPCODE 4: String print
-    StPrint([u8; 4]),
+    StPrint { offset: i32 },
     // OPCODE 5: Call
-    Call([u8; 4]),
+    Call { offset: i32 },
     // OPCODE 6: Return
-    Return([u8; 4]),
+    Return { offset: i32 },
     // OPCODE 7: Unconditional goto
-    Goto([u8; 4]),
-    BinaryIf([u8; 4]),
-    UnaryIf([u8; 4]),
-    Dup(i32),
+    Goto { offset: i32 },
+    // OPCODE 8: Binary if
+    BinaryIf { cond: Cond, offset: i32 },
+    // OPCODE 9: Unary if
+    UnaryIf { cond: UnaryCond, offset: i32 },
+    // OPCODE 12: Dup
+    Dup { offset: i32 },
     // OPCODE 13: Print
-    Print(i32,u8),
+    Print { offset: i32, format: PrintFormat },
     // OPCODE 14: Dump
     Dump,
     // OPCODE 15: Push
-    Push(Option<i32>)
+    Push { value: i32 },
 }
 
 #[allow(dead_code)]
@@ -62,6 +99,7 @@ impl Instruction {
 
         // Extract the upper 4 bits of the last byte, the opcode
         let opcode = bytes[3] >> 4;
+        let word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
         // println!("Bytes: {}", helper::convert_hex(bytes));
         // println!("Opcode: {:#04x}", opcode);
 
@@ -71,20 +109,23 @@ impl Instruction {
                 let operation = bytes[3] & 0x0F; // (SN) get lower order nibble
                 match operation
                 {
-                    0x0 => Some(Instruction::Exit),
-                    0x1 => Some(Instruction::Swap(bytes[0..4].try_into().unwrap())),
+                    0x0 => Some(Instruction::Exit { code: bytes[0] }), // exit code lives in the low byte
+                    0x1 => Some(Instruction::Swap {
+                        from: sign_extend((word >> 12) & 0xFFF, 12) << 2,  // bits 23:12, in words
+                        to: sign_extend(word & 0xFFF, 12) << 2,            // bits 11:0, in words
+                    }),
                     0x2 => Some(Instruction::Nop),
                     0x4 => Some(Instruction::Input),
-                    0x5 => Some(Instruction::StInput(bytes[0..4].try_into().unwrap())),
-                    0xF => Some(Instruction::Debug),
+                    0x5 => Some(Instruction::StInput { max_chars: word & 0x00FF_FFFF }),
+                    0xF => Some(Instruction::Debug { value: word & 0x00FF_FFFF }), // bits [23:0] are free for the programmer
                     _ => None,
                 }
             }
             0x1 => {
                 // Pop instruction
-                let offset = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3] & 0x0F]);
+                let offset = sign_extend(word & 0x0FFF_FFFF, 28);
                 // println!("Pop value: {}", offset);
-                Some(Instruction::Pop(offset))
+                Some(Instruction::Pop { offset })
             }
             0x2 => {
                 //Binary Arithmetic
@@ -115,44 +156,61 @@ impl Instruction {
                 }
             }
             0x4 => { // (SN) Take 4 bytes as your argument, you need bits 2-27 for the 'Stack Relative Offset'
-                Some(Instruction::StPrint(bytes[0..4].try_into().unwrap()))
+                Some(Instruction::StPrint { offset: sign_extend(word & 0x0FFF_FFFC, 28) })
             }
             0x5 => { // (SN) Take 4 bytes as your argument, you need bits 2-27 for the 'PC-Relative Offset'
-                Some(Instruction::Call(bytes[0..4].try_into().unwrap()))
+                Some(Instruction::Call { offset: sign_extend(word & 0x0FFF_FFFC, 28) })
             }
             0x6 => { // (SN) Take 4 bytes as your argument, you need bits 2-27 for the 'Stack Offset'
-                Some(Instruction::Return(bytes[0..4].try_into().unwrap()))
+                Some(Instruction::Return { offset: sign_extend(word & 0x0FFF_FFFC, 28) })
             }
             0x7 => { // (SN) Take 4 bytes as your argument, you need bits 2-27 for the 'PC-Relative Offset'
-                Some(Instruction::Goto(bytes[0..4].try_into().unwrap()))
+                Some(Instruction::Goto { offset: sign_extend(word & 0x0FFF_FFFC, 28) })
             }
-            0x8 => { 
-                Some(Instruction::BinaryIf(bytes[0..4].try_into().unwrap()))
+            0x8 => { // Condition in bits 27:25, bits 2-24 for the 'PC-Relative Offset'
+                let cond = match (bytes[3] >> 1) & 0x7 {
+                    0 => Cond::Eq,
+                    1 => Cond::Ne,
+                    2 => Cond::Lt,
+                    3 => Cond::Gt,
+                    4 => Cond::Le,
+                    5 => Cond::Ge,
+                    _ => return None,
+                };
+                Some(Instruction::BinaryIf { cond, offset: sign_extend(word & 0x01FF_FFFC, 25) })
             },
             0x9 => { // (AS) Unary if condition
-                Some(Instruction::UnaryIf(bytes[0..4].try_into().unwrap()))
+                let cond = match (bytes[3] >> 1) & 0x3 {
+                    0 => UnaryCond::Ez,
+                    1 => UnaryCond::Nz,
+                    2 => UnaryCond::Mi,
+                    _ => UnaryCond::Pl,
+                };
+                Some(Instruction::UnaryIf { cond, offset: sign_extend(word & 0x01FF_FFFC, 25) })
             },
             0xC => { // (SN) Take 4 bytes as your argument, you need bits 2-27 for the 'Stack Relative Offset'
-            let offset = i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3] & 0x0F]);
-            Some(Instruction::Dup(offset))
-
+                Some(Instruction::Dup { offset: sign_extend(word & 0x0FFF_FFFC, 28) })
             }
             0xD => { // (SN) Take 4 bytes as your argument, bits 2-27 'Stack Relative Offset' bits 01 'format'
-
-                let word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
-                let print_bit = (word & 0b11) as u8;
-                let offset = ((word & !0b11) & 0x0FFF_FFFF) as i32;
+                let format = match word & 0b11 {
+                    0 => PrintFormat::Decimal,
+                    1 => PrintFormat::Hex,
+                    2 => PrintFormat::Binary,
+                    _ => PrintFormat::Octal,
+                };
+                let offset = sign_extend(word & 0x0FFF_FFFC, 28);
                 //println!("Offset: {}, Print Bit: {}",offset,print_bit);
-                Some(Instruction::Print(offset, print_bit))
+                Some(Instruction::Print { offset, format })
             }
             0xE => { // (SN) No arguments
                 Some(Instruction::Dump)
             }
             0xF => {
                 // Push instruction
-                let value = i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3] & 0x0F]);
+                // The immediate is 28 bits, sign-extended to a full 32-bit value here and only here
+                let value = sign_extend(word & 0x0FFF_FFFF, 28);
                 // println!("Push value: {}", value);
-                Some(Instruction::Push(Some(value)))
+                Some(Instruction::Push { value })
             }
             
             _ => None, // Unknown opcode
@@ -160,45 +218,22 @@ impl Instruction {
     }
 
     // Executes instruction
-    pub fn execute(&self, machine: &mut crate::machine::Machine) {
+    pub fn execute<I: MachineIo>(&self, machine: &mut Machine<I>) -> Result<(), VmError> {
         //println!("Executing: {:?}", self);
         match self {
             // OPCODE 0: Miscellaneous instructions
-            Instruction::Exit => {},
+            Instruction::Exit { .. } => {},
             // OPCODE 0: Miscellaneous instructions
-            Instruction::Swap(bytes) => {
-                let i = u32::from_le_bytes(*bytes);
-                let from_raw = (i >> 12) & 0xFFF; //get bits 12-23
-                let from: i32 = if (from_raw & 1 << 11) != 0 // if 11th bit set, then negative
-                {
-                    ((from_raw | 0xFFFFF000) << 2) as i32 //sign extend if negative
-                } 
-                else 
-                {
-                    (from_raw << 2) as i32
-                };
-                let to_raw = i & 0xFFF;
-                let to: i32 = if (to_raw & 1 << 11) != 0 
-                {
-                    ((to_raw | 0xFFFFF000) << 2) as i32
-                } 
-                else 
-                {
-                    (to_raw << 2) as i32
-                };
-                
+            Instruction::Swap { from, to } => {
                 let sp = machine.get_stack_pointer() as i32;
                 // println!("swap sp = {}", sp);
-                // println!("swap raw from={}, to={}", from_raw, to_raw);
-                // println!("swap adjusted from={}, to={}", from, to);
-                // println!("swap final addresses {} {}", (sp + from) as usize, (sp + to) as usize);
-                machine.swap((sp + from) as usize, (sp + to) as usize);
+                // println!("swap from={}, to={}", from, to);
+                machine.swap(sp as i64 + *from as i64, sp as i64 + *to as i64)?;
             },
             // OPCODE 0: Miscellaneous instructions
             Instruction::Nop => {},
             Instruction::Input => {
-                let mut ipt: String = String::new();
-                let _ = stdin().read_line(&mut ipt);
+                let ipt: String = machine.read_line()?;
                 let trimmed = ipt.trim();
                 
                 let value = if trimmed.starts_with("0x") || trimmed.starts_with("0X") 
@@ -214,22 +249,22 @@ impl Instruction {
                     trimmed.parse::<i32>().unwrap_or(0)
                 };
                 
-                machine.stack_push(value);
+                machine.stack_push(value)?;
             },
-            Instruction::StInput(bytes) => {
-                let max_chars = (u32::from_le_bytes(*bytes) & 0x00FFFFFF) as usize; //maximum string length
+            Instruction::StInput { max_chars } => {
+                let max_chars = *max_chars as usize; //maximum string length
 
                 //get input from user
-                let mut ipt: String = String::new();
-                let _ = stdin().read_line(&mut ipt);
+                let ipt: String = machine.read_line()?;
                 let trimmed_str = ipt.trim();
 
                 // if input is empty or just whitespace, push 0
-                if trimmed_str.is_empty() { machine.stack_push(0); return; } 
+                if trimmed_str.is_empty() { machine.stack_push(0)?; return Ok(()); } 
 
                 // clean out whitespace and get important info about string
                 let my_string: Vec<char> = trimmed_str.chars().collect();
                 let len = usize::min(my_string.len(), max_chars);
+                if len == 0 { machine.stack_push(0)?; return Ok(()); }
                 let mut remainder = len % 3;
 
                 let mut first_push: u32 = 0;
@@ -239,8 +274,8 @@ impl Instruction {
                     {
                         first_push |= (my_string[len - 1] as u32) << 16;
                         first_push |= (my_string[len - 2] as u32) << 8;
-                        first_push |= (my_string[len - 3] as u32) << 0;
-                        machine.stack_push(first_push as i32);
+                        first_push |= my_string[len - 3] as u32;
+                        machine.stack_push(first_push as i32)?;
                         remainder = 3;
                     },
 
@@ -249,19 +284,19 @@ impl Instruction {
                         // 1 is default padding value
                         first_push |= 1 << 16;
                         first_push |= 1 << 8;
-                        first_push |= (my_string[len - 1] as u32) << 0;
-                        machine.stack_push(first_push as i32);
+                        first_push |= my_string[len - 1] as u32;
+                        machine.stack_push(first_push as i32)?;
                     },
 
                     2 =>
                     {
                         first_push |= 1 << 16;
                         first_push |= (my_string[len - 1] as u32) << 8;
-                        first_push |= (my_string[len - 2] as u32) << 0;
-                        machine.stack_push(first_push as i32);
+                        first_push |= my_string[len - 2] as u32;
+                        machine.stack_push(first_push as i32)?;
                     },
                 
-                    _ => return,
+                    _ => return Ok(()),
                 }
 
                 //println!("First Push: {:08b} {:08b} {:08b} {:08b}", (first_push >> 24) & 0xFF, (first_push >> 16) & 0xFF, (first_push >> 8) & 0xFF, first_push & 0xFF);
@@ -273,127 +308,122 @@ impl Instruction {
                     let mut push_val: u32 = 0;
                     push_val |= (my_string[index - 1] as u32) << 16;
                     push_val |= (my_string[index - 2] as u32) << 8;
-                    push_val |= (my_string[index - 3] as u32) << 0;
+                    push_val |= my_string[index - 3] as u32;
                     push_val |= 0b0001 << 24; // continue flag
-                    machine.stack_push(push_val as i32);
+                    machine.stack_push(push_val as i32)?;
                     index -= 3;
                 }
             },
             // OPCODE 0: Miscellaneous instructions
-            Instruction::Debug => {},
+            // Nothing to do here on purpose: the machine reports it in the Step it
+            // returns, and whoever is driving it decides what to do. The Debugger
+            // (what the machine binary uses by default at a terminal) stops at it
+            // with a prompt, a plain run() carries on like a nop.
+            Instruction::Debug { .. } => {},
             // OPCODE 1: Pop instructions
-            Instruction::Pop(offset) => {
-                if machine.get_stack_pointer() < 4096
-                {
-                    let new_sp = machine.get_stack_pointer() + *offset as usize;
-                    //println!("current SP: {}, target SP: {}", machine.get_stack_pointer(), new_sp);
-                    if new_sp < 4096
-                    {
-                        machine.sp_jump(new_sp);
-                    }
-                    else 
-                    {
-                        machine.sp_jump(4096);
-                    }
+            Instruction::Pop { offset } => {
+                let new_sp = machine.get_stack_pointer() as i64 + *offset as i64;
+                //println!("current SP: {}, target SP: {}", machine.get_stack_pointer(), new_sp);
+                if new_sp < 0 {
+                    return Err(VmError::OutOfBounds { address: new_sp });
                 }
+                machine.stack_jump(new_sp as usize)?;
             },
             // OPCODE 2: Binary arithmetic
             Instruction::Add => {
-                let right = machine.stack_pop();
-                let left = machine.stack_pop();
-                machine.stack_push(left + right);
+                let right = machine.stack_pop()?;
+                let left = machine.stack_pop()?;
+                machine.stack_push(machine.arithmetic_mode().add(left, right)?)?;
                 //println!("\tADD: result: {}", machine.peek(machine.get_stack_pointer()));
             },
             // OPCODE 2: Binary arithmetic
             Instruction::Sub => {
-                let right = machine.stack_pop();
-                let left = machine.stack_pop();
-                machine.stack_push(left - right);
+                let right = machine.stack_pop()?;
+                let left = machine.stack_pop()?;
+                machine.stack_push(machine.arithmetic_mode().sub(left, right)?)?;
                 //println!("\tSUB: result: {}", machine.peek(machine.get_stack_pointer()));
             },
             // OPCODE 2: Binary arithmetic
             Instruction::Mul => {
-                let right = machine.stack_pop();
-                let left = machine.stack_pop();
-                machine.stack_push(left * right);
+                let right = machine.stack_pop()?;
+                let left = machine.stack_pop()?;
+                machine.stack_push(machine.arithmetic_mode().mul(left, right)?)?;
                 //println!("\tMUL: result: {}", machine.peek(machine.get_stack_pointer()));
             },
             // OPCODE 2: Binary arithmetic
             Instruction::Div => {
-                let right = machine.stack_pop();
-                let left = machine.stack_pop();
-                machine.stack_push(left / right);
+                let right = machine.stack_pop()?;
+                let left = machine.stack_pop()?;
+                if right == 0 {
+                    return Err(VmError::DivideByZero);
+                }
+                machine.stack_push(machine.arithmetic_mode().div(left, right)?)?;
                 //println!("\tDIV: result: {}", machine.peek(machine.get_stack_pointer()));
             },
             // OPCODE 2: Binary arithmetic
             Instruction::Rem => {
-                let right = machine.stack_pop();
-                let left = machine.stack_pop();
-                machine.stack_push(left % right);
+                let right = machine.stack_pop()?;
+                let left = machine.stack_pop()?;
+                if right == 0 {
+                    return Err(VmError::DivideByZero);
+                }
+                machine.stack_push(machine.arithmetic_mode().rem(left, right)?)?;
                 //println!("\tREM: result: {}", machine.peek(machine.get_stack_pointer()));
             },
             // OPCODE 2: Binary arithmetic
             Instruction::And => {
-                let right = machine.stack_pop();
-                let left = machine.stack_pop();
-                machine.stack_push(left & right);
+                let right = machine.stack_pop()?;
+                let left = machine.stack_pop()?;
+                machine.stack_push(left & right)?;
                 //println!("\tAND: result: {}", machine.peek(machine.get_stack_pointer()));
             },
             // OPCODE 2: Binary arithmetic
             Instruction::Or => {
-                let right = machine.stack_pop();
-                let left = machine.stack_pop();
-                machine.stack_push(left | right);
+                let right = machine.stack_pop()?;
+                let left = machine.stack_pop()?;
+                machine.stack_push(left | right)?;
                 //println!("\tOR: result: {}", machine.peek(machine.get_stack_pointer()));
             },
             // OPCODE 2: Binary arithmetic
             Instruction::Xor => {
-                let right = machine.stack_pop();
-                let left = machine.stack_pop();
-                machine.stack_push(left ^ right);
+                let right = machine.stack_pop()?;
+                let left = machine.stack_pop()?;
+                machine.stack_push(left ^ right)?;
                 //println!("\tXOR: result: {}", machine.peek(machine.get_stack_pointer()));
             },
             // OPCODE 2: Binary arithmetic
             Instruction::Lsl => {
-                let right = machine.stack_pop();
-                let left = machine.stack_pop();
-                machine.stack_push(left << right);
+                let right = machine.stack_pop()?;
+                let left = machine.stack_pop()?;
+                machine.stack_push(shift_left(left, right))?;
                 //println!("\tLSL: result: {}", machine.peek(machine.get_stack_pointer()));
             },
             // OPCODE 2: Binary arithmetic
             Instruction::Lsr => {
-                let right = machine.stack_pop();
-                let left = machine.stack_pop();
-                machine.stack_push(left >> right);
+                let right = machine.stack_pop()?;
+                let left = machine.stack_pop()?;
+                machine.stack_push(shift_right_logical(left, right))?;
                 //println!("\tLSR: result: {}", machine.peek(machine.get_stack_pointer()));
             },
             // OPCODE 2: Binary arithmetic
-            Instruction::Asr => { // ! LOW CERTAINTY THAT THIS IS HOW ASR WORKS
-                let right = machine.stack_pop();
-                let left = machine.stack_pop();
-                let nth_bit = 1 << 27;
-                if left & nth_bit == 0
-                {
-                    machine.stack_push(left >> right);
-                }
-                else
-                {
-                    machine.stack_push(left >> right | 1 << 28);    
-                }
+            Instruction::Asr => {
+                let right = machine.stack_pop()?;
+                let left = machine.stack_pop()?;
+                machine.stack_push(shift_right_arithmetic(left, right))?;
                 //println!("ASR result: {}", machine.peek(machine.get_stack_pointer()));
             },
             // OPCODE 3: Unary arithmetic
             Instruction::Neg => {
-                let mut stack_val = machine.stack_pop();
+                let mut stack_val = machine.stack_pop()?;
                 //println!("\tNEG: Popped Val: {}",stack_val);
-                stack_val *= -1;
+                stack_val = machine.arithmetic_mode().neg(stack_val)?;
                 //println!("\tNEG: Negated Val: {}",stack_val);
 
-                machine.stack_push(stack_val);
+                machine.stack_push(stack_val)?;
             },
             // OPCODE 3: Unary arithmetic
             Instruction::Not => {
-                let mut stack_val = machine.stack_pop();
+                let mut stack_val = machine.stack_pop()?;
                 //println!("\tNOT: Popped Val: {}",stack_val);
                 //println!("\tNOT: Popped Hex Val: {:08x}",stack_val);
 
@@ -401,26 +431,20 @@ impl Instruction {
                 //println!("\tNOT: Negated Val: {}",stack_val);
                 //println!("\tNOT: Negated Hex Val: {:08x}",stack_val);
 
-                machine.stack_push(stack_val);
+                machine.stack_push(stack_val)?;
             },
             // OPCODE 4: String print
-            Instruction::StPrint(bytes) => {
-                let raw = u32::from_le_bytes(*bytes) & 0x0FFFFFFC;
-                let sro = if (raw & (1 << 27)) != 0
-                {
-                    (raw | 0xF0000000) as i32
-                }
-                else
-                {
-                    raw as i32
-                };
-
-                let mut index = machine.get_stack_pointer() as i32 + sro;
+            Instruction::StPrint { offset } => {
+                let mut index = machine.get_stack_pointer() as i32 + offset;
                 //println!("STPRINT index: {}", index);
                 let mut b: u8;
+                let mut text = String::new();
                 loop 
                 {
-                    if index > 4095 {break;} // out of memory
+                    if index as i64 >= machine.memory_size() as i64 {break;} // out of memory
+                    if index < 0 {
+                        return Err(VmError::OutOfBounds { address: index as i64 });
+                    }
 
                     b = *machine.get_byte(index as usize);
                     index += 1;
@@ -431,129 +455,87 @@ impl Instruction {
                         1 => continue,
                         _ => 
                         {
-                            print!("{}", b as char)
+                            text.push(b as char)
                         }
                     }
                 }
-                std::io::stdout().flush().unwrap();
+                machine.write_output(&text)?;
+                machine.flush_output()?;
             },
             // OPCODE 5: Call instructions
-            Instruction::Call(bytes) => {
-                let raw = u32::from_le_bytes(*bytes) & 0x0FFFFFFC;
-                //println!("\tCall raw: {}", raw);
-                let pc_ro = if (raw & 1 << 27) != 0
-                {
-                    (raw | 0xF0000000) as i32
-                }
-                else
-                {
-                    raw as i32
-                };
+            Instruction::Call { offset } => {
                 //if pc_ro == 0 {pc_ro = machine.get_program_counter() as i32;}
                 //println!("\tCALL: PC {}", machine.get_program_counter() + 4);
-                machine.stack_push((machine.get_program_counter() + 4) as i32);
-                machine.pc_jump( (machine.get_program_counter() as i32 + pc_ro) as usize);
+                let call_site = machine.get_program_counter();
+                let caller_sp = machine.get_stack_pointer();
+                let entry = branch_target(machine, *offset)?;
+                machine.stack_push((call_site + 4) as i32)?;
+                machine.enter_frame(Frame { call_site, entry, return_address: call_site + 4, caller_sp });
+                machine.pc_jump(entry);
                 //println!("\tCALL: pushed instruction: {} to SP {}", (machine.get_program_counter() + 4), machine.get_stack_pointer());
             },
             // OPCODE 6: Return instructions
-            Instruction::Return(bytes) => {
-                let raw = u32::from_le_bytes(*bytes) & 0x0FFFFFFC;
-                let sro = if (raw & (1 << 27)) != 0
-                {
-                    (raw | 0xF0000000) as i32
-                }
-                else
-                {
-                    raw as i32
-                };
+            Instruction::Return { offset } => {
                 //let ret_addr = machine.peek((machine.get_stack_pointer() as i32 + sro) as usize);
                 // println!("\tRETURN sro: {} SP: {}", sro, machine.get_stack_pointer() - 4);
                 //machine.sp_jump((4096 - sro) as usize);
-                machine.sp_jump((machine.get_stack_pointer() as i32 + sro) as usize);
+                let new_sp = machine.get_stack_pointer() as i64 + *offset as i64;
+                if new_sp < 0 {
+                    return Err(VmError::OutOfBounds { address: new_sp });
+                }
+                machine.stack_jump(new_sp as usize)?;
                 //machine.sp_jump(sro as usize);
                 // println!("\tRETURN SP: {}", machine.get_stack_pointer());
-                let ret_addr = machine.stack_pop();
+                let ret_addr = machine.stack_pop()? as u32 as usize;
                 // println!("\tRETURN: popped {} from {}", ret_addr as usize, machine.get_stack_pointer() - 4);
-                machine.pc_jump(ret_addr as usize);
+                machine.leave_frame(ret_addr)?;
+                machine.pc_jump(ret_addr);
             },
             // OPCODE 7: Unconditional goto
-            Instruction::Goto(bytes) => {
-                let raw = u32::from_le_bytes(*bytes) & 0x0FFFFFFC;
-                let pc_ro = if (raw & 1 << 27) != 0
-                {
-                    (raw | 0xF0000000) as i32
-                }
-                else
-                {
-                    raw as i32
-                };
-                let target = machine.get_program_counter() as i32 + pc_ro;
-                machine.pc_jump(target as usize);
-                //println!("\tGOTO: pc_ro: {} target: {}", pc_ro, target);
+            Instruction::Goto { offset } => {
+                let target = branch_target(machine, *offset)?;
+                machine.pc_jump(target);
+                //println!("\tGOTO: offset: {} target: {}", offset, target);
             },
-            Instruction::BinaryIf(bytes) => {
-                let condition = (bytes[3] >> 1) & 0x7;
-                let raw = u32::from_le_bytes(*bytes) & 0x00FF_FFFC;
-                let pc_ro = if (raw & 1 << 23) != 0
-                {
-                    (raw | 0xFF00_0000) as i32
-                }
-                else
-                {
-                    raw as i32
-                };
-                let jump_target = machine.get_program_counter() as i32 + pc_ro;
-                let right = machine.peek(machine.get_stack_pointer());
-                let left = machine.peek(machine.get_stack_pointer() + 4);                
-                // println!("Bif: jump attempt to {} on condition {}", jump_target, condition);
+            Instruction::BinaryIf { cond, offset } => {
+                let right = machine.stack_peek(0)?;
+                let left = machine.stack_peek(4)?;
+                // println!("Bif: jump attempt to {} on condition {:?}", jump_target, cond);
                 // println!("left: {} right: {}", left, right);
-                let full_send = match condition
-                {
-                    0 => left == right,
-                    1 => left != right,
-                    2 => left <  right,
-                    3 => left >  right,
-                    4 => left <= right,
-                    5 => left >= right,
-                    _ => {machine.pc_increment(); return;}
+                let full_send = match cond
+                {
+                    Cond::Eq => left == right,
+                    Cond::Ne => left != right,
+                    Cond::Lt => left <  right,
+                    Cond::Gt => left >  right,
+                    Cond::Le => left <= right,
+                    Cond::Ge => left >= right,
                 };
 
                 if full_send
                 {
-                    machine.pc_jump(jump_target as usize);
+                    machine.pc_jump(branch_target(machine, *offset)?);
                 }
                 else
                 {
                     machine.pc_increment();
                 }
             },
-            Instruction::UnaryIf(bytes) => {
-                let condition = (bytes[3] >> 1) & 0x3;
-                let raw = u32::from_le_bytes(*bytes) & 0x00FFFFFC;
-                let pc_ro = if (raw & 1 << 23) != 0
-                {
-                    (raw | 0xFF00_0000) as i32
-                }
-                else
-                {
-                    raw as i32
-                };
-                let jump_target = machine.get_program_counter() as i32 + pc_ro;
-                let val = machine.peek(machine.get_stack_pointer());
+            Instruction::UnaryIf { cond, offset } => {
+                let val = machine.stack_peek(0)?;
                 
-                // println!("Uif: jump attempt to {} on condition {}", jump_target, condition);
+                // println!("Uif: jump attempt to {} on condition {:?}", jump_target, cond);
                 // println!("val: {}", val);
-                let full_send = match condition
+                let full_send = match cond
                 {
-                    0 => val == 0,
-                    1 => val != 0,
-                    2 => val <  0,
-                    3 => val >  0,
-                    _ => {machine.pc_increment(); return;}
+                    UnaryCond::Ez => val == 0,
+                    UnaryCond::Nz => val != 0,
+                    UnaryCond::Mi => val <  0,
+                    UnaryCond::Pl => val >  0,
                 };
                 if full_send
                 {
-                    machine.pc_jump(jump_target as usize);
+                    machine.pc_jump(branch_target(machine, *offset)?);
                 }
                 else
                 {
@@ -564,55 +546,268 @@ impl Instruction {
             // OPCODE 12: Dup instructions
             // Mike - April 30th
             
-            Instruction::Dup (offset) => {
+            Instruction::Dup { offset } => {
                 //println!("\tDUP: Provided offset: {}",offset);
 
-                let offset_usize = *offset as usize;
-                let peeked_val = machine.peek(machine.get_stack_pointer() + offset_usize);
-                machine.stack_push(peeked_val);
+                let peeked_val = machine.stack_peek(*offset)?;
+                machine.stack_push(peeked_val)?;
 
                 //println!("\tDUP: Pushing peeked val: {}",peeked_val);
 
             },
             // OPCODE 13: Print instructions
 
-            Instruction::Print (offset, print_bit) => {
-                let offset_usize = *offset as usize;
-                let offset_val = machine.peek(machine.get_stack_pointer() + offset_usize);
+            Instruction::Print { offset, format } => {
+                let offset_val = machine.stack_peek(*offset)?;
                 //println!("\tPRINT: Offset Num {}", offset);
-                //println!("\tPRINT: Val peeked: {} | Print bit: {}",offset_val,print_bit);
-                // Decimal
-                match *print_bit {
-                    0 => println!("{}", offset_val),                // Decimal
-                    1 => println!("0x{:x}", offset_val),            // Hex
-                    2 => println!("0b{:b}", offset_val),            // Binary
-                    3 => println!("0o{:o}", offset_val),            // Octal
-                    _ => println!("\tPRINT: Invalid format: {}", print_bit) 
-                    // Note, ^ this should mathematically never ocur 
-                }
+                //println!("\tPRINT: Val peeked: {} | Format: {:?}",offset_val,format);
+                let text = match format {
+                    PrintFormat::Decimal => format!("{}\n", offset_val),
+                    PrintFormat::Hex => format!("0x{:x}\n", offset_val),
+                    PrintFormat::Binary => format!("0b{:b}\n", offset_val),
+                    PrintFormat::Octal => format!("0o{:o}\n", offset_val),
+                };
+                machine.write_output(&text)?;
             },
             // OPCODE 14: Dump instructions
             Instruction::Dump => {
                 let stack_val = machine.get_stack_pointer();
                 // println!("\tDUMP: stack_val = {}",stack_val);
-                if stack_val >= 4096{
+                if stack_val >= machine.memory_size(){
                     // println!("\tDUMP: Nothing on the stack to display, Performing as NOP");
                     // functionally a NOP
                 }else{
                     // println!("\tDUMP: Executing...");
-                    for i in (stack_val..4096).step_by(4){
+                    for i in (stack_val..machine.memory_size()).step_by(4){
                     // println!("\tDUMP: {:x}",machine.peek(i));
                         
-                        println!("{:04x}: {:08x}",i,machine.peek(i));
+                        let line = format!("{:04x}: {:08x}\n",i,machine.peek(i));
+                        machine.write_output(&line)?;
                     }
                 }
             },
             // OPCODE 15: Push instructions
-            Instruction::Push(value) => {
-                let v = value.unwrap_or(0);
-                //println!("\tPUSH: Pushing value {}", v);
-                machine.stack_push(v);
+            Instruction::Push { value } => {
+                //println!("\tPUSH: Pushing value {}", value);
+                machine.stack_push(*value)?;
             },
         }
+        Ok(())
+    }
+}
+
+impl Instruction {
+    // Encode into the 4 little-endian bytes decode_instruction reads,
+    // so that decode_instruction(&i.encode()) == Some(i). Operands that
+    // don't fit their field (see fits_encoding) would be cut down silently,
+    // so debug builds catch them here.
+    pub fn encode(&self) -> [u8; 4] {
+        debug_assert!(self.fits_encoding(), "{:?} has an operand its encoding can't hold", self);
+        let word: u32 = match self {
+            // OPCODE 0: Miscellaneous, sub-opcode in bits 27:24
+            Instruction::Exit { code } => *code as u32,
+            Instruction::Swap { from, to } => {
+                0x0100_0000 | ((*from as u32 >> 2) & 0xFFF) << 12 | ((*to as u32 >> 2) & 0xFFF)
+            }
+            Instruction::Nop => 0x0200_0000,
+            Instruction::Input => 0x0400_0000,
+            Instruction::StInput { max_chars } => 0x0500_0000 | (max_chars & 0x00FF_FFFF),
+            Instruction::Debug { value } => 0x0F00_0000 | (value & 0x00FF_FFFF),
+            // OPCODE 1: Pop
+            Instruction::Pop { offset } => 0x1000_0000 | (*offset as u32 & 0x0FFF_FFFF),
+            // OPCODE 2: Binary arithmetic
+            Instruction::Add => 0x2000_0000,
+            Instruction::Sub => 0x2100_0000,
+            Instruction::Mul => 0x2200_0000,
+            Instruction::Div => 0x2300_0000,
+            Instruction::Rem => 0x2400_0000,
+            Instruction::And => 0x2500_0000,
+            Instruction::Or => 0x2600_0000,
+            Instruction::Xor => 0x2700_0000,
+            Instruction::Lsl => 0x2800_0000,
+            Instruction::Lsr => 0x2900_0000,
+            Instruction::Asr => 0x2B00_0000,
+            // OPCODE 3: Unary arithmetic
+            Instruction::Neg => 0x3000_0000,
+            Instruction::Not => 0x3100_0000,
+            // OPCODES 4-7: 28-bit offset, multiple of four
+            Instruction::StPrint { offset } => 0x4000_0000 | (*offset as u32 & 0x0FFF_FFFC),
+            Instruction::Call { offset } => 0x5000_0000 | (*offset as u32 & 0x0FFF_FFFC),
+            Instruction::Return { offset } => 0x6000_0000 | (*offset as u32 & 0x0FFF_FFFC),
+            Instruction::Goto { offset } => 0x7000_0000 | (*offset as u32 & 0x0FFF_FFFC),
+            // OPCODES 8-9: condition in bits 27:25, 25-bit offset
+            Instruction::BinaryIf { cond, offset } => {
+                let code = match cond {
+                    Cond::Eq => 0,
+                    Cond::Ne => 1,
+                    Cond::Lt => 2,
+                    Cond::Gt => 3,
+                    Cond::Le => 4,
+                    Cond::Ge => 5,
+                };
+                0x8000_0000 | code << 25 | (*offset as u32 & 0x01FF_FFFC)
+            }
+            Instruction::UnaryIf { cond, offset } => {
+                let code = match cond {
+                    UnaryCond::Ez => 0,
+                    UnaryCond::Nz => 1,
+                    UnaryCond::Mi => 2,
+                    UnaryCond::Pl => 3,
+                };
+                0x9000_0000 | code << 25 | (*offset as u32 & 0x01FF_FFFC)
+            }
+            // OPCODE 12: Dup
+            Instruction::Dup { offset } => 0xC000_0000 | (*offset as u32 & 0x0FFF_FFFC),
+            // OPCODE 13: Print, format in bits 1:0
+            Instruction::Print { offset, format } => {
+                let format = match format {
+                    PrintFormat::Decimal => 0,
+                    PrintFormat::Hex => 1,
+                    PrintFormat::Binary => 2,
+                    PrintFormat::Octal => 3,
+                };
+                0xD000_0000 | (*offset as u32 & 0x0FFF_FFFC) | format
+            }
+            // OPCODE 14: Dump
+            Instruction::Dump => 0xE000_0000,
+            // OPCODE 15: Push, 28-bit immediate
+            Instruction::Push { value } => 0xF000_0000 | (*value as u32 & 0x0FFF_FFFF),
+        };
+        word.to_le_bytes()
+    }
+
+    // Whether every operand fits its field, i.e. encode then decode gives
+    // back the same instruction
+    pub fn fits_encoding(&self) -> bool {
+        match self {
+            Instruction::Swap { from, to } => fits_offset(*from, 14) && fits_offset(*to, 14),
+            Instruction::StInput { max_chars: value } | Instruction::Debug { value } => *value <= 0x00FF_FFFF,
+            Instruction::Pop { offset } => fits_signed(*offset, 28),
+            Instruction::StPrint { offset }
+            | Instruction::Call { offset }
+            | Instruction::Return { offset }
+            | Instruction::Goto { offset }
+            | Instruction::Dup { offset }
+            | Instruction::Print { offset, .. } => fits_offset(*offset, 28),
+            Instruction::BinaryIf { offset, .. } | Instruction::UnaryIf { offset, .. } => fits_offset(*offset, 25),
+            Instruction::Push { value } => fits_signed(*value, 28),
+            _ => true,
+        }
     }
-}
\ No newline at end of file
+
+    // PC-relative offset of call, goto and the ifs, None for everything else
+    pub fn branch_offset(&self) -> Option<i32> {
+        match self {
+            Instruction::Call { offset }
+            | Instruction::Goto { offset }
+            | Instruction::BinaryIf { offset, .. }
+            | Instruction::UnaryIf { offset, .. } => Some(*offset),
+            _ => None,
+        }
+    }
+}
+
+// Shift counts are the whole 32-bit word read as unsigned, so a negative
+// count is a huge one. Shifting by 32 or more shifts every bit out: lsl and
+// lsr give 0, asr gives 0 or -1 depending on the sign.
+fn shift_left(value: i32, count: i32) -> i32 {
+    (value as u32).checked_shl(count as u32).unwrap_or(0) as i32
+}
+
+// Zeroes come in at the top, whatever the sign
+fn shift_right_logical(value: i32, count: i32) -> i32 {
+    (value as u32).checked_shr(count as u32).unwrap_or(0) as i32
+}
+
+// Copies of the sign bit come in at the top
+fn shift_right_arithmetic(value: i32, count: i32) -> i32 {
+    value >> (count as u32).min(31)
+}
+
+// PC + offset for goto, call and the ifs, faulting below address 0
+fn branch_target<I: MachineIo>(machine: &Machine<I>, offset: i32) -> Result<usize, VmError> {
+    let target = machine.get_program_counter() as i64 + offset as i64;
+    if target < 0 {
+        return Err(VmError::OutOfBounds { address: target });
+    }
+    Ok(target as usize)
+}
+
+// Sign-extend the low `bits` bits of value
+fn sign_extend(value: u32, bits: u32) -> i32 {
+    let shift = 32 - bits;
+    ((value << shift) as i32) >> shift
+}
+
+// Whether value survives being cut down to `bits` bits and sign-extended
+fn fits_signed(value: i32, bits: u32) -> bool {
+    sign_extend(value as u32, bits) == value
+}
+
+// Same for a byte offset, whose low two bits aren't stored
+fn fits_offset(offset: i32, bits: u32) -> bool {
+    offset % 4 == 0 && fits_signed(offset, bits)
+}
+
+// Mnemonic and operands in the same syntax the .asm files use
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Instruction::Exit { code } => write!(f, "exit {}", code),
+            Instruction::Swap { from, to } => write!(f, "swap {} {}", from, to),
+            Instruction::Nop => write!(f, "nop"),
+            Instruction::Input => write!(f, "input"),
+            Instruction::StInput { max_chars } => write!(f, "stinput 0x{:x}", max_chars),
+            // The operand is optional in .asm and defaults to 0
+            Instruction::Debug { value: 0 } => write!(f, "debug"),
+            Instruction::Debug { value } => write!(f, "debug 0x{:x}", value),
+            Instruction::Pop { offset } => write!(f, "pop {}", offset),
+            Instruction::Add => write!(f, "add"),
+            Instruction::Sub => write!(f, "sub"),
+            Instruction::Mul => write!(f, "mul"),
+            Instruction::Div => write!(f, "div"),
+            Instruction::Rem => write!(f, "rem"),
+            Instruction::And => write!(f, "and"),
+            Instruction::Or => write!(f, "or"),
+            Instruction::Xor => write!(f, "xor"),
+            Instruction::Lsl => write!(f, "lsl"),
+            Instruction::Lsr => write!(f, "lsr"),
+            Instruction::Asr => write!(f, "asr"),
+            Instruction::Neg => write!(f, "neg"),
+            Instruction::Not => write!(f, "not"),
+            Instruction::StPrint { offset } => write!(f, "stprint {}", offset),
+            Instruction::Call { offset } => write!(f, "call {}", offset),
+            Instruction::Return { offset } => write!(f, "return {}", offset),
+            Instruction::Goto { offset } => write!(f, "goto {}", offset),
+            Instruction::BinaryIf { cond, offset } => {
+                let mnemonic = match cond {
+                    Cond::Eq => "ifeq",
+                    Cond::Ne => "ifne",
+                    Cond::Lt => "iflt",
+                    Cond::Gt => "ifgt",
+                    Cond::Le => "ifle",
+                    Cond::Ge => "ifge",
+                };
+                write!(f, "{} {}", mnemonic, offset)
+            }
+            Instruction::UnaryIf { cond, offset } => {
+                let mnemonic = match cond {
+                    UnaryCond::Ez => "ifez",
+                    UnaryCond::Nz => "ifnz",
+                    UnaryCond::Mi => "ifmi",
+                    UnaryCond::Pl => "ifpl",
+                };
+                write!(f, "{} {}", mnemonic, offset)
+            }
+            Instruction::Dup { offset } => write!(f, "dup {}", offset),
+            Instruction::Print { offset, format } => match format {
+                PrintFormat::Decimal => write!(f, "print {}", offset),
+                PrintFormat::Hex => write!(f, "printh {}", offset),
+                PrintFormat::Binary => write!(f, "printb {}", offset),
+                PrintFormat::Octal => write!(f, "printo {}", offset),
+            },
+            Instruction::Dump => write!(f, "dump"),
+            Instruction::Push { value } => write!(f, "push {}", value),
+        }
+    }
+}
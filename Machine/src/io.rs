@@ -0,0 +1,109 @@
+// Computer Science 365: VM Project, Machine
+// Mike Hall, Seth Nelson, Sarah Pastor, Alan Saucer
+
+// Console I/O for input, stinput, print, stprint and dump.
+// The machine never touches stdin/stdout directly, it goes through one of these.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Cursor, Write};
+use std::path::Path;
+
+pub trait MachineIo {
+    // Read one line (including the newline) into buf, returns 0 at end of input
+    fn read_line(&mut self, buf: &mut String) -> io::Result<usize>;
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+// The process console, what the machine binary uses
+#[derive(Debug, Default)]
+pub struct StdIo;
+
+impl MachineIo for StdIo {
+    fn read_line(&mut self, buf: &mut String) -> io::Result<usize> {
+        io::stdin().read_line(buf)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        io::stdout().write_all(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+// Scripted input and captured output, for tests and embedding
+#[derive(Debug, Default)]
+pub struct BufferIo {
+    input: Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+impl BufferIo {
+    pub fn new<T: Into<Vec<u8>>>(input: T) -> Self {
+        Self {
+            input: Cursor::new(input.into()),
+            output: Vec::new(),
+        }
+    }
+
+    // Everything the program has written so far
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    pub fn into_output(self) -> Vec<u8> {
+        self.output
+    }
+}
+
+impl MachineIo for BufferIo {
+    fn read_line(&mut self, buf: &mut String) -> io::Result<usize> {
+        self.input.read_line(buf)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.output.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Input read from one file, output written to another
+#[derive(Debug)]
+pub struct FileIo {
+    input: BufReader<File>,
+    output: BufWriter<File>,
+}
+
+impl FileIo {
+    pub fn new(input: File, output: File) -> Self {
+        Self {
+            input: BufReader::new(input),
+            output: BufWriter::new(output),
+        }
+    }
+
+    // Open the input file for reading and create (or truncate) the output file
+    pub fn open<P: AsRef<Path>, Q: AsRef<Path>>(input: P, output: Q) -> io::Result<Self> {
+        Ok(Self::new(File::open(input)?, File::create(output)?))
+    }
+}
+
+impl MachineIo for FileIo {
+    fn read_line(&mut self, buf: &mut String) -> io::Result<usize> {
+        self.input.read_line(buf)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.output.write_all(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
@@ -0,0 +1,189 @@
+// Computer Science 365: VM Project, Machine
+// Mike Hall, Seth Nelson, Sarah Pastor, Alan Saucer
+
+// Loader for .v images
+
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+// Every .v file starts with these four bytes
+pub const MAGIC: [u8; 4] = [0xde, 0xad, 0xbe, 0xef];
+
+// Container layout written by Image::to_bytes, all words little-endian:
+//
+//   magic            de ad be ef
+//   header           0xA000_0000 | version
+//   entry point      byte address execution starts at
+//   section count
+//   sections         kind, address, length, then length bytes padded to a word
+//
+// Opcode 0xA is not an instruction, so a legacy file (magic followed
+// straight by code) can never be mistaken for a container.
+pub const VERSION: u32 = 2;
+const HEADER_TAG: u32 = 0xA000_0000;
+const HEADER_TAG_MASK: u32 = 0xF000_0000;
+
+// Section kinds
+const SECTION_CODE: u32 = 1;
+const SECTION_DATA: u32 = 2;
+const SECTION_SYMBOLS: u32 = 3;
+
+#[derive(Debug)]
+pub enum LoadError {
+    Open(std::io::Error),
+    Read(std::io::Error),
+    TooSmall,
+    BadMagic([u8; 4]),
+    UnsupportedVersion(u32),
+    Truncated,                                  // A header or section runs past the end of the file
+    BadSection { kind: u32, address: u32 },     // Unknown kind, a second code section or code not at 0
+    BadEntry(u32),                              // Entry point not on a word boundary
+    EntryOutsideCode { entry: u32, code_len: usize }, // Entry point past the end of the code section
+    DoesNotFit { address: usize, len: usize },  // A section runs past the end of memory
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Open(e) => write!(f, "Error opening file: {}", e),
+            LoadError::Read(e) => write!(f, "Error reading file: {}", e),
+            LoadError::TooSmall => write!(f, "File is too small to contain magic bytes."),
+            LoadError::BadMagic(magic) => write!(f, "ERROR: magic doesn't match: {:?}", magic),
+            LoadError::UnsupportedVersion(version) => write!(f, "ERROR: unsupported .v version {}", version),
+            LoadError::Truncated => write!(f, "ERROR: file ends in the middle of a header or section"),
+            LoadError::BadSection { kind, address } => {
+                write!(f, "ERROR: bad section (kind {}, address 0x{:04x})", kind, address)
+            }
+            LoadError::BadEntry(entry) => write!(f, "ERROR: entry point 0x{:04x} is not word aligned", entry),
+            LoadError::EntryOutsideCode { entry, code_len } => {
+                write!(f, "ERROR: entry point 0x{:04x} is outside the 0x{:04x} bytes of code", entry, code_len)
+            }
+            LoadError::DoesNotFit { address, len } => {
+                write!(f, "ERROR: {} bytes at 0x{:04x} do not fit in memory", len, address)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+// Initialised memory outside the code, copied in before the program starts
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataSegment {
+    pub address: usize,
+    pub bytes: Vec<u8>,
+}
+
+// A program ready to be loaded into a Machine
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    pub code: Vec<u8>,              // Loaded at address 0
+    pub entry: usize,               // Where the program counter starts
+    pub data: Vec<DataSegment>,
+    pub symbols: Option<Vec<u8>>,   // Symbol section, carried through untouched
+}
+
+impl Image {
+    // Wrap raw instruction bytes (no magic) as an image
+    pub fn new(code: Vec<u8>) -> Self {
+        Self { code, entry: 0, data: Vec::new(), symbols: None }
+    }
+
+    // Parse the contents of a .v file, checking the magic bytes
+    pub fn from_bytes(bytes: &[u8]) -> Result<Image, LoadError> {
+        if bytes.len() < 4 {
+            return Err(LoadError::TooSmall);
+        }
+
+        let magic: [u8; 4] = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        if magic != MAGIC {
+            return Err(LoadError::BadMagic(magic));
+        }
+
+        match read_word(bytes, 4) {
+            Some(header) if header & HEADER_TAG_MASK == HEADER_TAG => {
+                Image::parse_container(header & !HEADER_TAG_MASK, &bytes[8..])
+            }
+            // Legacy file, everything after the magic is code
+            _ => Ok(Image::new(bytes[4..].to_vec())),
+        }
+    }
+
+    // Everything after the header word of a container
+    fn parse_container(version: u32, bytes: &[u8]) -> Result<Image, LoadError> {
+        if version != VERSION {
+            return Err(LoadError::UnsupportedVersion(version));
+        }
+        let entry = read_word(bytes, 0).ok_or(LoadError::Truncated)?;
+        let count = read_word(bytes, 4).ok_or(LoadError::Truncated)?;
+        if !entry.is_multiple_of(4) {
+            return Err(LoadError::BadEntry(entry));
+        }
+
+        let mut image = Image::new(Vec::new());
+        image.entry = entry as usize;
+        let mut code = None;
+        let mut offset = 8;
+        for _ in 0..count {
+            let kind = read_word(bytes, offset).ok_or(LoadError::Truncated)?;
+            let address = read_word(bytes, offset + 4).ok_or(LoadError::Truncated)?;
+            let len = read_word(bytes, offset + 8).ok_or(LoadError::Truncated)? as usize;
+            offset += 12;
+            let payload = bytes.get(offset..offset + len).ok_or(LoadError::Truncated)?.to_vec();
+            offset += len.next_multiple_of(4);
+
+            match kind {
+                SECTION_CODE if code.is_none() && address == 0 => code = Some(payload),
+                SECTION_DATA => image.data.push(DataSegment { address: address as usize, bytes: payload }),
+                SECTION_SYMBOLS if image.symbols.is_none() => image.symbols = Some(payload),
+                _ => return Err(LoadError::BadSection { kind, address }),
+            }
+        }
+        image.code = code.unwrap_or_default();
+        // An empty program can only start (and immediately end) at 0
+        if entry != 0 && entry as usize >= image.code.len() {
+            return Err(LoadError::EntryOutsideCode { entry, code_len: image.code.len() });
+        }
+        Ok(image)
+    }
+
+    // Read and parse a .v file from disk
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Image, LoadError> {
+        let mut f = File::open(path).map_err(LoadError::Open)?;
+        let mut buffer = Vec::new();
+        f.read_to_end(&mut buffer).map_err(LoadError::Read)?;
+        Image::from_bytes(&buffer)
+    }
+
+    // Serialise as a current-version container, from_bytes(&i.to_bytes()) == Ok(i)
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut sections: Vec<(u32, usize, &[u8])> = vec![(SECTION_CODE, 0, &self.code)];
+        for segment in &self.data {
+            sections.push((SECTION_DATA, segment.address, &segment.bytes));
+        }
+        if let Some(symbols) = &self.symbols {
+            sections.push((SECTION_SYMBOLS, 0, symbols));
+        }
+
+        let mut bytes = MAGIC.to_vec();
+        for word in [HEADER_TAG | VERSION, self.entry as u32, sections.len() as u32] {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        for (kind, address, payload) in sections {
+            for word in [kind, address as u32, payload.len() as u32] {
+                bytes.extend_from_slice(&word.to_le_bytes());
+            }
+            bytes.extend_from_slice(payload);
+            bytes.resize(bytes.len().next_multiple_of(4), 0);
+        }
+        bytes
+    }
+}
+
+// Little-endian word at offset, None past the end
+fn read_word(bytes: &[u8], offset: usize) -> Option<u32> {
+    let word = bytes.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+}
@@ -0,0 +1,88 @@
+// Computer Science 365: VM Project, Machine
+// Mike Hall, Seth Nelson, Sarah Pastor, Alan Saucer
+
+// Infinite loop detection
+// Without input the machine is deterministic, so if it is ever in exactly the
+// same state twice (PC, SP and every byte of RAM) it will go round the same
+// loop forever. Rather than remembering every state we use Brent's cycle
+// finding: keep one checkpoint, compare every step against it, and move the
+// checkpoint forward at doubling distances. Any loop is caught within about
+// twice its length, in constant memory.
+//
+// Comparing RAM every step would be slow, so the detector keeps a hash of
+// memory that Machine updates word by word as it writes. RAM is only compared
+// byte for byte when the hashes already agree, so a report is never a guess.
+
+struct Checkpoint {
+    pc: usize,
+    sp: usize,
+    hash: u64,
+    ram: Vec<u8>,
+}
+
+pub(crate) struct LoopDetector {
+    memory_hash: u64,               // XOR of word_hash over every word of RAM
+    checkpoint: Option<Checkpoint>,
+    distance: u64,                  // Steps since the checkpoint was taken
+    power: u64,                     // Distance at which the checkpoint moves on
+}
+
+impl LoopDetector {
+    pub(crate) fn new(ram: &[u8]) -> Self {
+        let mut detector = Self { memory_hash: 0, checkpoint: None, distance: 0, power: 1 };
+        detector.rehash(ram);
+        detector
+    }
+
+    // Start over from scratch, after RAM was replaced wholesale
+    pub(crate) fn rehash(&mut self, ram: &[u8]) {
+        self.memory_hash = ram
+            .chunks_exact(4)
+            .enumerate()
+            .fold(0, |hash, (index, word)| hash ^ word_hash(index * 4, word));
+        self.forget();
+    }
+
+    // The word at address is about to change from old to new
+    pub(crate) fn write(&mut self, address: usize, old: &[u8], new: &[u8]) {
+        self.memory_hash ^= word_hash(address, old) ^ word_hash(address, new);
+    }
+
+    // Input makes earlier states meaningless, the same state can carry on differently
+    pub(crate) fn forget(&mut self) {
+        self.checkpoint = None;
+        self.distance = 0;
+        self.power = 1;
+    }
+
+    // True if this state has been seen before
+    pub(crate) fn check(&mut self, pc: usize, sp: usize, ram: &[u8]) -> bool {
+        if let Some(checkpoint) = &self.checkpoint
+            && checkpoint.pc == pc
+            && checkpoint.sp == sp
+            && checkpoint.hash == self.memory_hash
+            && checkpoint.ram == ram
+        {
+            return true;
+        }
+
+        self.distance += 1;
+        if self.checkpoint.is_none() || self.distance >= self.power {
+            self.checkpoint = Some(Checkpoint { pc, sp, hash: self.memory_hash, ram: ram.to_vec() });
+            self.distance = 0;
+            self.power *= 2;
+        }
+        false
+    }
+}
+
+// splitmix64 of the word and where it lives, so equal words at different
+// addresses don't cancel each other out
+fn word_hash(address: usize, word: &[u8]) -> u64 {
+    let word = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+    let mut x = (address as u64) << 32 | word as u64;
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
@@ -1,117 +1,591 @@
 // Computer Science 365: VM Project, Machine
 // Mike Hall, Seth Nelson, Sarah Pastor, Alan Saucer
 
-/*
-    Dear Dr. Marz. Forgive us for not figuring out Return.
-*/
-
 // Machine
 
-#[path = "instruction.rs"] mod instruction;
-use std::process::exit;
-use instruction::Instruction;
+use std::fmt;
+use crate::arithmetic::ArithmeticMode;
+use crate::error::{Fault, VmError};
+use crate::instruction::Instruction;
+use crate::io::{MachineIo, StdIo};
+use crate::loader::{Image, LoadError};
+use crate::loop_detector::LoopDetector;
+use crate::symbols::Symbols;
+use crate::trace::Tracer;
+
+// RAM the machine gets unless it is asked for something else
+pub const DEFAULT_MEMORY_SIZE: usize = 4096;
+
+// Most RAM the machine can have. Stack and PC offsets are at most 28 bits, and
+// addresses get added to them as i32.
+pub const MAX_MEMORY_SIZE: usize = 1 << 28;
+
+// Why a memory size can't be used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemorySizeError {
+    NotWords { bytes: usize },              // Zero, or not a whole number of words
+    TooBig { bytes: usize },                // Past MAX_MEMORY_SIZE
+}
+
+impl fmt::Display for MemorySizeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemorySizeError::NotWords { bytes } => write!(f, "memory size must be a positive multiple of 4, not {}", bytes),
+            MemorySizeError::TooBig { bytes } => write!(f, "memory size must be at most {} bytes, not {}", MAX_MEMORY_SIZE, bytes),
+        }
+    }
+}
+
+impl std::error::Error for MemorySizeError {}
 
-pub struct Machine {
-    ram: [u8; 4096],
+// RAM has to be a whole number of words, at least one and at most MAX_MEMORY_SIZE
+pub fn validate_memory_size(bytes: usize) -> Result<usize, MemorySizeError> {
+    if bytes < 4 || !bytes.is_multiple_of(4) {
+        return Err(MemorySizeError::NotWords { bytes });
+    }
+    if bytes > MAX_MEMORY_SIZE {
+        return Err(MemorySizeError::TooBig { bytes });
+    }
+    Ok(bytes)
+}
+
+pub struct Machine<I: MachineIo = StdIo> {
+    io: I,                          // Where input comes from and output goes to
+    tracer: Option<Tracer>,         // Logs every step when set
+    symbols: Option<Symbols>,       // Labels and source lines for showing PCs
+    ram: Vec<u8>,
+    decoded: Vec<Option<Instruction>>, // One entry per word of the code region, None if it doesn't decode
     stack_pointer: usize,
     program_counter: usize,
     last_instruction_index: usize,  // This does not change after reading everything.
+    steps: u64,                     // Instructions executed so far
+    max_steps: Option<u64>,         // Give up after this many instructions
+    loop_detector: Option<LoopDetector>, // Stop as soon as a machine state repeats
+    strict: bool,                   // Reading or popping past the stack base, or a return that doesn't match its call, is a fault
+    protect_code: bool,             // Writing into the loaded code is a fault
+    arithmetic: ArithmeticMode,     // What overflowing add/sub/mul/div/neg do
+    frames: Vec<Frame>,             // Calls that haven't returned yet, innermost last
+    fault: Option<Fault>,           // Set once an instruction faults, the machine stays stopped
+}
+
+// One call that hasn't returned yet. call pushes return_address just below
+// caller_sp, and the matching return pops that same address. It may pop it
+// from somewhere else: a callee can swap the return address deeper and hand
+// results back on the stack (GetInputs in calc.asm does).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub call_site: usize,           // PC of the call instruction
+    pub entry: usize,               // Where the call jumped to
+    pub return_address: usize,      // PC of the instruction after the call
+    pub caller_sp: usize,           // SP before the return address was pushed
+}
+
+// Why the machine stopped running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    Exit(i32),              // An exit instruction was executed with this code
+    EndOfProgram,           // The program counter ran off the end of the program
+    StackCollision { pc: usize, sp: usize }, // The instruction at pc grew the stack down into the code
+    Fault(Fault),           // An instruction faulted
+    Quit,                   // Stopped from the debugger
+    StepLimit { pc: usize, steps: u64 }, // The instruction budget ran out before the instruction at pc
+    InfiniteLoop { pc: usize },          // The machine came back to a state it had already been in
+}
+
+// What a single call to Machine::step did
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    pub pc: usize,                          // Where the instruction was fetched from
+    pub instruction: Option<Instruction>,   // None if nothing could be executed
+    pub program_counter: usize,             // PC after the step
+    pub stack_pointer: usize,               // SP after the step
+    pub halted: Option<ExitReason>,         // Set once the machine has stopped
+}
+
+impl ExitReason {
+    // Status the machine process should exit with
+    pub fn code(&self) -> i32 {
+        match self {
+            ExitReason::Exit(code) => *code,
+            ExitReason::EndOfProgram | ExitReason::Quit => 0,
+            ExitReason::Fault(_) | ExitReason::StackCollision { .. } => 1,
+            // Same status timeout(1) uses for a program it had to stop
+            ExitReason::StepLimit { .. } | ExitReason::InfiniteLoop { .. } => 124,
+        }
+    }
+
+    // Where the machine was when it was stopped for something other than
+    // finishing, for pointing at the culprit
+    pub fn pc(&self) -> Option<usize> {
+        match self {
+            ExitReason::Fault(fault) => Some(fault.pc),
+            ExitReason::StackCollision { pc, .. }
+            | ExitReason::StepLimit { pc, .. }
+            | ExitReason::InfiniteLoop { pc } => Some(*pc),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExitReason::Exit(code) => write!(f, "exit instruction with code {}", code),
+            ExitReason::EndOfProgram => write!(f, "end of program reached"),
+            ExitReason::StackCollision { pc, sp } => {
+                write!(f, "stack overflow into code at PC=0x{:04x}, SP=0x{:04x}", pc, sp)
+            }
+            ExitReason::Fault(fault) => write!(f, "fault: {}", fault),
+            ExitReason::Quit => write!(f, "stopped from the debugger"),
+            ExitReason::StepLimit { pc, steps } => {
+                write!(f, "gave up after {} instructions at PC=0x{:04x}", steps, pc)
+            }
+            ExitReason::InfiniteLoop { pc } => {
+                write!(f, "infinite loop: machine state repeated at PC=0x{:04x}", pc)
+            }
+        }
+    }
+}
+
+impl Default for Machine {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Machine {
-    // Constructor
+    // Constructor, talks to the process console
     pub fn new() -> Self {
+        Self::with_memory(DEFAULT_MEMORY_SIZE)
+    }
+
+    // Constructor with `bytes` of RAM instead of the default 4 KiB.
+    // Panics if validate_memory_size rejects bytes.
+    pub fn with_memory(bytes: usize) -> Self {
+        if let Err(error) = validate_memory_size(bytes) {
+            panic!("{}", error);
+        }
         Self {
-            ram: [0; 4096],
-            stack_pointer: 4096,
+            io: StdIo,
+            tracer: None,
+            symbols: None,
+            ram: vec![0; bytes],
+            decoded: Vec::new(),
+            stack_pointer: bytes,
             program_counter: 0,
             last_instruction_index: 0, // Default to 0
+            steps: 0,
+            max_steps: None,
+            loop_detector: None,
+            strict: false,
+            protect_code: false,
+            arithmetic: ArithmeticMode::default(),
+            frames: Vec::new(),
+            fault: None,
+        }
+    }
+}
+
+impl<I: MachineIo> Machine<I> {
+    // Swap out the console for another MachineIo, e.g.
+    // Machine::new().with_io(BufferIo::new("5\n"))
+    pub fn with_io<J: MachineIo>(self, io: J) -> Machine<J> {
+        Machine {
+            io,
+            tracer: self.tracer,
+            symbols: self.symbols,
+            ram: self.ram,
+            decoded: self.decoded,
+            stack_pointer: self.stack_pointer,
+            program_counter: self.program_counter,
+            last_instruction_index: self.last_instruction_index,
+            steps: self.steps,
+            max_steps: self.max_steps,
+            loop_detector: self.loop_detector,
+            strict: self.strict,
+            protect_code: self.protect_code,
+            arithmetic: self.arithmetic,
+            frames: self.frames,
+            fault: self.fault,
         }
     }
 
+    pub fn io(&self) -> &I {
+        &self.io
+    }
+
+    pub fn io_mut(&mut self) -> &mut I {
+        &mut self.io
+    }
+
+    pub fn into_io(self) -> I {
+        self.io
+    }
+
+    // Start (or with None, stop) logging every executed instruction
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    pub fn tracer_mut(&mut self) -> Option<&mut Tracer> {
+        self.tracer.as_mut()
+    }
+
+    // Stop with ExitReason::StepLimit once this many instructions have run
+    pub fn set_max_steps(&mut self, max_steps: Option<u64>) {
+        self.max_steps = max_steps;
+    }
+
+    // Stop with ExitReason::InfiniteLoop when the machine gets back into a
+    // state it has been in before, with no input read in between
+    pub fn set_loop_detection(&mut self, enabled: bool) {
+        self.loop_detector = if enabled { Some(LoopDetector::new(&self.ram)) } else { None };
+    }
+
+    // Strict mode turns reads and pops past the stack base into
+    // StackUnderflow faults. Off by default, which keeps the old behavior
+    // (such reads give 0 and SP stops at the base) the reference tests expect.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    // Make the loaded code read-only, so a push or swap that would overwrite
+    // an instruction faults with VmError::CodeWrite instead
+    pub fn set_code_protection(&mut self, protect: bool) {
+        self.protect_code = protect;
+    }
+
+    pub fn set_arithmetic_mode(&mut self, mode: ArithmeticMode) {
+        self.arithmetic = mode;
+    }
+
+    pub fn arithmetic_mode(&self) -> ArithmeticMode {
+        self.arithmetic
+    }
 
-    pub fn run(&mut self)
+    // Calls in progress, outermost first
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    // Instructions executed so far
+    pub fn get_steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn set_symbols(&mut self, symbols: Option<Symbols>) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> Option<&Symbols> {
+        self.symbols.as_ref()
+    }
+
+    // An address the way it is shown to people, with its label and source
+    // line when there are symbols, ex: 0x0018 <Menu+8 calc.asm:14>
+    pub fn describe_address(&self, address: usize) -> String {
+        match self.symbols.as_ref().and_then(|symbols| symbols.describe(address)) {
+            Some(location) => format!("0x{:04x} <{}>", address, location),
+            None => format!("0x{:04x}", address),
+        }
+    }
+
+    // Calls in progress, innermost first, one line each, ex:
+    //   #0 0x0134 <GoXor calc.asm:79> called 0x00d4 <GetInputs calc.asm:57>
+    pub fn backtrace(&self) -> String {
+        self.frames
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(number, frame)| {
+                format!(
+                    "  #{} {} called {}\n",
+                    number,
+                    self.describe_address(frame.call_site),
+                    self.describe_address(frame.entry)
+                )
+            })
+            .collect()
+    }
+
+    // Run until the machine halts
+    pub fn run(&mut self) -> ExitReason
     {
-        // println!("Starting machine execution...");
-        // println!("Initial PC: {}, SP: {}", self.program_counter, self.stack_pointer);
-        
-        // Calculate last_instruction_index based on loaded program
-        // Assume all bytes have valid instructions
-        // last_instruction_index = next multiple of 4 after last non-zero byte
-        for i in (0..self.ram.len()).rev() {
-            if self.ram[i] != 0 {
-                // Found the last non-zero byte, round up to the next multiple of 4
-                self.last_instruction_index = ((i + 1) + 3) & !3;
-                break;
+        loop {
+            // Nothing to record without a tracer, so skip straight to executing
+            let step = if self.tracer.is_some() { self.step() } else { self.execute_step() };
+            if let Some(reason) = step.halted {
+                return reason;
             }
         }
-        // println!("Last instruction index: {}", self.last_instruction_index);
-
-
-
-        // ! 3 Exit Conditions:
-        // !    - stack_pointer < last_instruction_index
-        // *            Stack has overwritten the instructions, exit gracefully
-        // !    - program_counter >= last_instruction_index
-        // *            The program_counter can not equal or go above the last_instruction_index
-        // *            For example: 3 instructions = I1 = (0,1,2,3), I2 = (4,5,6,7), I3 = (8,9,10,11) and the program_counter will equal 12
-        // !    - An Exit instruction is given
-        // *            An exit instruction needs to be handled with the proper code
-        
-        // Main execution loop
-        while self.stack_pointer > self.last_instruction_index 
-              && self.program_counter < self.last_instruction_index {
-            
-            // Get the next 4 bytes for the current instruction
-            let current_instr_bytes = [
-                self.ram[self.program_counter],
-                self.ram[self.program_counter + 1],
-                self.ram[self.program_counter + 2],
-                self.ram[self.program_counter + 3],
-            ];
-            
-            // Decode the instruction
-            if let Some(instruction) = Instruction::decode_instruction(&current_instr_bytes) {
-                // Execute the instruction
-                match instruction {
-                    Instruction::Exit => {
-                        //println!("Exit instruction encountered. Stopping execution.");
-                        exit(current_instr_bytes[0] as i32);
-                    },
-                    Instruction::Goto(_) | Instruction::BinaryIf(_) | 
-                    Instruction::UnaryIf(_) | Instruction::Return(_) | Instruction::Call(_) => {
-                        instruction.execute(self);
-                    }
-                    _ => {
-                        // Execute the instruction (which might update PC, SP, etc.)
-                        instruction.execute(self);
-                        self.pc_increment();
-                    }
+    }
+
+    // ! 3 Exit Conditions:
+    // !    - stack_pointer < last_instruction_index
+    // *            Stack has overwritten the instructions, reported as a StackCollision
+    // !    - program_counter >= last_instruction_index
+    // *            The program_counter can not equal or go above the last_instruction_index
+    // *            For example: 3 instructions = I1 = (0,1,2,3), I2 = (4,5,6,7), I3 = (8,9,10,11) and the program_counter will equal 12
+    // !    - An Exit instruction is given
+    // *            An exit instruction needs to be handled with the proper code
+    //
+    // The first two are checked here, before anything is fetched, along with
+    // any fault that already stopped the machine
+    pub fn halt_reason(&self) -> Option<ExitReason>
+    {
+        if let Some(fault) = self.fault {
+            Some(ExitReason::Fault(fault))
+        } else if self.stack_pointer <= self.last_instruction_index {
+            Some(ExitReason::StackCollision { pc: self.program_counter, sp: self.stack_pointer })
+        } else if self.program_counter >= self.last_instruction_index {
+            Some(ExitReason::EndOfProgram)
+        } else {
+            None
+        }
+    }
+
+    // Fetch and execute exactly one instruction
+    pub fn step(&mut self) -> Step
+    {
+        let sp_before = self.stack_pointer;
+        // Read before executing, the instruction can write over its own word
+        let word = self.peek(self.program_counter) as u32;
+        let step = self.execute_step();
+        if let Some(mut tracer) = self.tracer.take() {
+            tracer.record(self, &step, word, sp_before);
+            self.tracer = Some(tracer);
+        }
+        step
+    }
+
+    // Inlined so run()'s loop doesn't pay a call per instruction
+    #[inline(always)]
+    fn execute_step(&mut self) -> Step
+    {
+        let pc = self.program_counter;
+        let mut step = Step {
+            pc,
+            instruction: None,
+            program_counter: pc,
+            stack_pointer: self.stack_pointer,
+            halted: self.halt_reason(),
+        };
+        if step.halted.is_some() {
+            return step;
+        }
+        step.halted = self.limit_reason();
+        if step.halted.is_some() {
+            return step;
+        }
+
+        // Look the instruction up in the pre-decoded code region
+        let instruction = match self.fetch(pc) {
+            Some(instruction) => instruction,
+            None => {
+                let word = u32::from_le_bytes([
+                    self.ram[pc],
+                    self.ram[pc + 1],
+                    self.ram[pc + 2],
+                    self.ram[pc + 3],
+                ]);
+                let fault = Fault { pc, error: VmError::IllegalInstruction { word } };
+                self.fault = Some(fault);
+                step.halted = Some(ExitReason::Fault(fault));
+                return step;
+            }
+        };
+
+        // Execute the instruction
+        let result = match instruction {
+            Instruction::Exit { code } => {
+                //println!("Exit instruction encountered. Stopping execution.");
+                step.halted = Some(ExitReason::Exit(code as i32));
+                Ok(())
+            },
+            Instruction::Goto { .. } | Instruction::BinaryIf { .. } | 
+            Instruction::UnaryIf { .. } | Instruction::Return { .. } | Instruction::Call { .. } => {
+                instruction.execute(self)
+            }
+            _ => {
+                // Execute the instruction (which might update PC, SP, etc.)
+                // A faulting instruction leaves PC on itself
+                let result = instruction.execute(self);
+                if result.is_ok() {
+                    self.pc_increment();
+                }
+                result
+            }
+        };
+        if let Err(error) = result {
+            self.fault = Some(Fault { pc, error });
+            step.halted = Some(ExitReason::Fault(Fault { pc, error }));
+        } else if self.stack_pointer <= self.last_instruction_index {
+            // Caught here rather than by halt_reason so it names the instruction that did it
+            step.halted = Some(ExitReason::StackCollision { pc, sp: self.stack_pointer });
+        }
+        self.steps += 1;
+        if let (Instruction::Input | Instruction::StInput { .. }, Some(detector)) = (instruction, &mut self.loop_detector) {
+            detector.forget();
+        }
+
+        // println!("After execution: PC={}, SP={}", self.program_counter, self.stack_pointer);
+        step.instruction = Some(instruction);
+        step.program_counter = self.program_counter;
+        step.stack_pointer = self.stack_pointer;
+        step
+    }
+
+    // Budget and loop checks, made before each instruction is fetched
+    fn limit_reason(&mut self) -> Option<ExitReason>
+    {
+        let pc = self.program_counter;
+        if let Some(max_steps) = self.max_steps && self.steps >= max_steps {
+            return Some(ExitReason::StepLimit { pc, steps: self.steps });
+        }
+        if let Some(detector) = &mut self.loop_detector && detector.check(pc, self.stack_pointer, &self.ram) {
+            return Some(ExitReason::InfiniteLoop { pc });
+        }
+        None
+    }
+
+    // Decoded instruction at pc. Code is decoded once at load time, so this
+    // only falls back to decoding RAM if pc isn't on a word boundary.
+    fn fetch(&self, pc: usize) -> Option<Instruction>
+    {
+        if pc.is_multiple_of(4) && let Some(entry) = self.decoded.get(pc / 4) {
+            return *entry;
+        }
+        Instruction::decode_instruction(&self.ram[pc..pc + 4])
+    }
+
+    // Decode every word of the code region
+    fn decode_code(&mut self)
+    {
+        self.decoded = self.ram[..self.last_instruction_index]
+            .chunks_exact(4)
+            .map(Instruction::decode_instruction)
+            .collect();
+    }
+
+    // Every write to RAM goes through here so a program that writes over
+    // its own code (e.g. the stack growing into it) never runs a stale
+    // decoded instruction, and the loop detector's memory hash stays current
+    fn write_word(&mut self, address: usize, bytes: [u8; 4])
+    {
+        if let Some(detector) = &mut self.loop_detector {
+            // The aligned words the write lands in, two if it is unaligned
+            let start = address & !3;
+            let end = (address + 4).next_multiple_of(4);
+            let mut new = [0u8; 8];
+            new[..end - start].copy_from_slice(&self.ram[start..end]);
+            new[address - start..address - start + 4].copy_from_slice(&bytes);
+            for offset in (0..end - start).step_by(4) {
+                let at = start + offset;
+                detector.write(at, &self.ram[at..at + 4], &new[offset..offset + 4]);
+            }
+        }
+        self.ram[address..address + 4].copy_from_slice(&bytes);
+        if address < self.last_instruction_index {
+            // An unaligned write can touch two words, refresh both ends
+            for byte in [address, address + 3] {
+                let index = byte / 4;
+                if let Some(entry) = self.decoded.get_mut(index) {
+                    *entry = self.ram.get(index * 4..index * 4 + 4).and_then(Instruction::decode_instruction);
                 }
-            } else {
-                println!("Unknown instruction at PC={}. Skipping for now.", self.program_counter);
-                self.pc_increment();
             }
-            
-            // println!("After execution: PC={}, SP={}", self.program_counter, self.stack_pointer);
         }
-        
-        // Report why we stopped
-        if self.stack_pointer <= self.last_instruction_index {
-            // println!("Machine halted: Stack overflow into instruction area");
-        } else if self.program_counter > self.last_instruction_index {
-            // println!("Machine halted: End of program reached");
+    }
+
+    // Trap writes into the code region when it is read-only
+    fn check_writable(&self, address: usize) -> Result<(), VmError>
+    {
+        if self.protect_code && address < self.last_instruction_index {
+            return Err(VmError::CodeWrite { address });
+        }
+        Ok(())
+    }
+
+    fn read_word(&self, address: usize) -> [u8; 4]
+    {
+        [
+            self.ram[address],
+            self.ram[address + 1],
+            self.ram[address + 2],
+            self.ram[address + 3],
+        ]
+    }
+
+    // Load a parsed .v image into RAM and start at its entry point
+    pub fn load_image(&mut self, image: &Image) -> Result<(), LoadError> {
+        let sections = std::iter::once((0, image.code.len()))
+            .chain(image.data.iter().map(|segment| (segment.address, segment.bytes.len())));
+        for (address, len) in sections {
+            if address + len > self.ram.len() {
+                return Err(LoadError::DoesNotFit { address, len });
+            }
+        }
+
+        self.load_bytes(&image.code)?;
+        for segment in &image.data {
+            self.ram[segment.address..segment.address + segment.bytes.len()].copy_from_slice(&segment.bytes);
+        }
+        // Data written over the code has to be decoded along with it
+        if image.data.iter().any(|segment| segment.address < self.last_instruction_index) {
+            self.decode_code();
+        }
+        if let Some(detector) = &mut self.loop_detector {
+            detector.rehash(&self.ram);
         }
-        
-        // Print final state
-        // println!("Final state - PC: {}, SP: {}", self.program_counter, self.stack_pointer);
+        self.program_counter = image.entry;
+        Ok(())
     }
 
     // Load bytes into RAM
-    pub fn load_bytes(&mut self, bytes: &[u8]) {
+    pub fn load_bytes(&mut self, bytes: &[u8]) -> Result<(), LoadError> {
+        if bytes.len() > self.ram.len() {
+            return Err(LoadError::DoesNotFit { address: 0, len: bytes.len() });
+        }
         self.ram[0..bytes.len()].copy_from_slice(bytes);
-        self.last_instruction_index = bytes.len();
+        // The program is exactly what was loaded, trailing zero words (exit 0,
+        // push 0) included. A partial last word is padded out with zeros.
+        self.last_instruction_index = bytes.len().next_multiple_of(4);
+        self.frames.clear();
+        self.fault = None;
         //println!("last instruction index = {}", self.last_instruction_index); // !DEBUGGING: make sure index is positioned correctly
+        self.decode_code();
+        if let Some(detector) = &mut self.loop_detector {
+            detector.rehash(&self.ram);
+        }
+        Ok(())
+    }
+
+    // Read one line of input for input/stinput
+    pub fn read_line(&mut self) -> Result<String, VmError>
+    {
+        let mut line = String::new();
+        self.io.read_line(&mut line).map_err(|e| VmError::Io(e.kind()))?;
+        Ok(line)
+    }
+
+    // Write program output for print/stprint/dump
+    pub fn write_output(&mut self, text: &str) -> Result<(), VmError>
+    {
+        self.io.write(text.as_bytes()).map_err(|e| VmError::Io(e.kind()))
+    }
+
+    pub fn flush_output(&mut self) -> Result<(), VmError>
+    {
+        self.io.flush().map_err(|e| VmError::Io(e.kind()))
+    }
+
+    // Size of RAM in bytes, the stack starts here and grows down
+    pub fn memory_size(&self) -> usize
+    {
+        self.ram.len()
     }
 
     // readonly function to examine memory
@@ -132,7 +606,7 @@ impl Machine {
 
     pub fn peek(&self, offset: usize) -> i32
     {
-        if offset > 4092
+        if offset + 4 > self.ram.len()
         {
             0
         }
@@ -148,12 +622,22 @@ impl Machine {
     }
 
     // (SN) Swap has to live here because it needs direct access to RAM
-    pub fn swap(&mut self, from: usize, to: usize)
+    // Exchanges the four-byte words at the two addresses
+    pub fn swap(&mut self, from: i64, to: i64) -> Result<(), VmError>
     {
         //println!("swap {} {}", from, to);
-        let temp = self.ram[from];
-        self.ram[from] = self.ram[to];
-        self.ram[to] = temp;
+        for address in [from, to] {
+            if address < 0 || address + 4 > self.ram.len() as i64 {
+                return Err(VmError::OutOfBounds { address });
+            }
+        }
+        let (from, to) = (from as usize, to as usize);
+        self.check_writable(from)?;
+        self.check_writable(to)?;
+        let (from_word, to_word) = (self.read_word(from), self.read_word(to));
+        self.write_word(from, to_word);
+        self.write_word(to, from_word);
+        Ok(())
     }
 
     // Change stack pointer
@@ -162,19 +646,77 @@ impl Machine {
     pub fn sp_jump(&mut self, value: usize)
     {
         self.stack_pointer = value;
-        if self.stack_pointer > 4096
+        if self.stack_pointer > self.ram.len()
         {
-            self.stack_pointer = 4096;
+            self.stack_pointer = self.ram.len();
         }
     }
 
+    // Move SP up to address for pop and return, past the stack base is an
+    // underflow in strict mode and stops at the base otherwise
+    pub fn stack_jump(&mut self, address: usize) -> Result<(), VmError>
+    {
+        if self.strict && address > self.ram.len() {
+            return Err(VmError::StackUnderflow { sp: self.stack_pointer });
+        }
+        self.sp_jump(address);
+        Ok(())
+    }
+
+    // Word at SP + offset for dup, print and the ifs. offset can be negative
+    // to read below SP, but not below address 0. Past the stack base is an
+    // underflow in strict mode and reads as 0 otherwise.
+    pub fn stack_peek(&self, offset: i32) -> Result<i32, VmError>
+    {
+        let address = self.stack_pointer as i64 + offset as i64;
+        if address < 0 {
+            return Err(VmError::OutOfBounds { address });
+        }
+        let address = address as usize;
+        if self.strict && address + 4 > self.ram.len() {
+            return Err(VmError::StackUnderflow { sp: self.stack_pointer });
+        }
+        Ok(self.peek(address))
+    }
+
+    // Record a call once its return address is on the stack
+    pub fn enter_frame(&mut self, frame: Frame)
+    {
+        self.frames.push(frame);
+    }
+
+    // A return just popped return_address. In strict mode it has to be the one
+    // the innermost call pushed. Otherwise anything goes, as the ISA allows:
+    // `push Label; return` is a plain indirect jump and matches no call, and a
+    // callee that left with goto leaves its frame behind until an outer call
+    // returns.
+    // The frame that returned is the one whose slot was popped, i.e. SP is back
+    // at its caller_sp. Recursive calls share a return address, so the address
+    // alone can pick a frame at the wrong depth; it is only the fallback for a
+    // return address the callee moved (GetInputs in calc.asm).
+    pub fn leave_frame(&mut self, return_address: usize) -> Result<(), VmError>
+    {
+        let expected = self.frames.last().copied();
+        if self.strict && expected.is_none_or(|frame| frame.return_address != return_address) {
+            return Err(VmError::ReturnMismatch { return_address, sp: self.stack_pointer, expected });
+        }
+        let sp = self.stack_pointer;
+        let index = self.frames.iter()
+            .rposition(|frame| frame.return_address == return_address && frame.caller_sp == sp)
+            .or_else(|| self.frames.iter().rposition(|frame| frame.return_address == return_address));
+        if let Some(index) = index {
+            self.frames.truncate(index);
+        }
+        Ok(())
+    }
+
     // Advances stack pointer
     pub fn sp_increment(&mut self)
     {
         self.stack_pointer += 4;
-        if self.stack_pointer > 4096
+        if self.stack_pointer > self.ram.len()
         {
-            self.stack_pointer = 4096;
+            self.stack_pointer = self.ram.len();
         }
     }
 
@@ -197,9 +739,9 @@ impl Machine {
     pub fn pc_increment(&mut self)
     {
         self.program_counter += 4;
-        if self.program_counter > 4096
+        if self.program_counter > self.ram.len()
         {
-            self.program_counter = 4096;
+            self.program_counter = self.ram.len();
         }
     }
 
@@ -207,39 +749,37 @@ impl Machine {
     // machine push
 /*
 */
-    pub fn stack_push(&mut self, value: i32)
+    // Every stack slot is four bytes, so SP must stay a multiple of four
+    fn check_alignment(&self) -> Result<(), VmError>
     {
-        self.sp_decrement();
-
-        let ext_value = if((value as u32) & 1 << 27) != 0
-        {
-            (value as u32 | 0xF0000000) as i32
+        if !self.stack_pointer.is_multiple_of(4) {
+            return Err(VmError::MisalignedStack { sp: self.stack_pointer });
         }
-        else
-        {
-            value
-        };
+        Ok(())
+    }
 
-        let bytes: [u8; 4] = ext_value.to_le_bytes();
-        for i in 0..4 
-        {
-            self.ram[self.stack_pointer + i] = bytes[i];
+    pub fn stack_push(&mut self, value: i32) -> Result<(), VmError>
+    {
+        self.check_alignment()?;
+        if self.stack_pointer < 4 {
+            return Err(VmError::StackOverflow { sp: self.stack_pointer });
         }
+        self.check_writable(self.stack_pointer - 4)?;
+        self.sp_decrement();
+        self.write_word(self.stack_pointer, value.to_le_bytes());
         //println!("\tstack_push: pushed {} to SP {}",value, self.stack_pointer);
+        Ok(())
     }
 
     // machine pop
-    pub fn stack_pop(&mut self) -> i32
+    pub fn stack_pop(&mut self) -> Result<i32, VmError>
     {
-        let mut bytes = [0u8; 4];
-        //if self.stack_pointer < 4096 { 
-            for i in 0..4
-            {
-                bytes[i] = self.ram[self.stack_pointer + i];
-            }
-        //}
-        let value = i32::from_le_bytes(bytes);
+        self.check_alignment()?;
+        if self.stack_pointer + 4 > self.ram.len() {
+            return Err(VmError::StackUnderflow { sp: self.stack_pointer });
+        }
+        let value = i32::from_le_bytes(self.read_word(self.stack_pointer));
         self.sp_increment();
-        value
+        Ok(value)
     }
 }
\ No newline at end of file
@@ -2,78 +2,253 @@
 // Mike Hall, Seth Nelson, Sarah Pastor, Alan Saucer
 
 use std::env;
-use std::io::prelude::*;
-use std::fs::File;
+use std::io::{self, IsTerminal};
+use std::path::Path;
 use std::process::exit;
 
-#[path = "machine.rs"] mod machine;
-#[path = "instruction.rs"] mod instruction;
-use instruction::Instruction;
+use machine::debugger::parse_address;
+use machine::disassembler::disassemble_with_symbols;
+use machine::helper::parse_number;
+use machine::{ArithmeticMode, Debugger, ExitReason, Image, LoadError, Machine, SymbolError, Symbols, Tracer, WatchTarget, DEFAULT_MEMORY_SIZE, validate_memory_size};
+
+// Command line options
+struct Options {
+    file_path: String,
+    debug: bool,        // Stop at debug instructions with an interactive prompt
+    breakpoints: Vec<String>,   // Addresses or labels, resolved once symbols are loaded
+    watchpoints: Vec<WatchTarget>,
+    trace: Option<Option<String>>,  // Log every instruction, to stderr or to the given file
+    disassemble: bool,  // Print the program instead of running it
+    symbols: Option<String>,    // Symbol file, instead of the one next to the .v
+    memory: usize,              // Bytes of RAM
+    max_steps: Option<u64>,     // Instruction budget
+    detect_loops: bool,         // Stop when the machine state repeats
+    strict: bool,               // Fault on stack underflow instead of reading zeroes
+    protect_code: bool,         // Fault on writes into the loaded code
+    arithmetic: ArithmeticMode,
+}
+
+// Most calls shown when the machine stops abnormally
+const BACKTRACE_FRAMES: usize = 16;
+
+fn usage(program: &str) {
+    println!("Usage: {} [options] <file.v>", program);
+    println!("Options:");
+    println!("  -D, --disassemble       print the program in .asm syntax instead of running it");
+    println!("  -d, --debug             stop at debug instructions with an interactive prompt");
+    println!("                          (the default when stdin is a terminal)");
+    println!("      --no-debug          run straight through debug instructions");
+    println!("  -b, --break <addr>      stop in the debugger before the instruction at addr (or label)");
+    println!("  -w, --watch <target>    stop in the debugger when a word changes (addr, or sp+N)");
+    println!("  -t, --trace[=file]      log every executed instruction to stderr (or file)");
+    println!("  -s, --symbols <file>    read labels and source lines from file (default: <file>.sym)");
+    println!("  -m, --memory <size>     bytes of RAM, e.g. 65536, 64K or 1M (default: 4K)");
+    println!("      --max-steps <n>     give up after executing n instructions");
+    println!("      --detect-loops      stop as soon as the program is provably stuck in a loop");
+    println!("      --strict            fault on popping or reading past the bottom of the stack,");
+    println!("                          and on a return that doesn't match the innermost call");
+    println!("      --protect-code      fault on any write into the loaded code");
+    println!("      --arithmetic <mode> what overflow does: wrapping (default), checked or saturating");
+}
+
+fn parse_args(args: &[String]) -> Option<Options> {
+    let mut file_path = None;
+    let mut debug = None;
+    let mut breakpoints = Vec::new();
+    let mut watchpoints = Vec::new();
+    let mut trace = None;
+    let mut disassemble = false;
+    let mut symbols = None;
+    let mut memory = DEFAULT_MEMORY_SIZE;
+    let mut max_steps = None;
+    let mut detect_loops = false;
+    let mut strict = false;
+    let mut protect_code = false;
+    let mut arithmetic = ArithmeticMode::default();
+
+    let mut args = args[1..].iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-D" | "--disassemble" => disassemble = true,
+            "-d" | "--debug" => debug = Some(true),
+            "--no-debug" => debug = Some(false),
+            "-b" | "--break" => breakpoints.push(args.next()?.clone()),
+            "-w" | "--watch" => watchpoints.push(WatchTarget::parse(args.next()?)?),
+            "-t" | "--trace" => trace = Some(None),
+            "-s" | "--symbols" => symbols = Some(args.next()?.clone()),
+            "-m" | "--memory" => memory = parse_memory_size(args.next()?)?,
+            "--max-steps" => max_steps = Some(u64::try_from(parse_number(args.next()?)?).ok()?),
+            "--detect-loops" => detect_loops = true,
+            "--strict" => strict = true,
+            "--protect-code" => protect_code = true,
+            "--arithmetic" => arithmetic = ArithmeticMode::parse(args.next()?)?,
+            _ if arg.starts_with("--trace=") => trace = Some(Some(arg["--trace=".len()..].to_string())),
+            _ if arg.starts_with('-') => return None,
+            _ if file_path.is_none() => file_path = Some(arg.clone()),
+            _ => return None,
+        }
+    }
+
+    // Unless told otherwise, debug instructions stop whenever someone is at the
+    // terminal to answer the prompt. Piped input (graders) runs straight through.
+    // Breakpoints and watchpoints only do anything inside the debugger.
+    let debug = debug.unwrap_or_else(|| io::stdin().is_terminal()) || !breakpoints.is_empty() || !watchpoints.is_empty();
+
+    Some(Options {
+        file_path: file_path?,
+        debug,
+        breakpoints,
+        watchpoints,
+        trace,
+        disassemble,
+        symbols,
+        memory,
+        max_steps,
+        detect_loops,
+        strict,
+        protect_code,
+        arithmetic,
+    })
+}
+
+// A byte count with an optional K or M suffix, ex: 4096, 0x1000, 64K, 1M,
+// that validate_memory_size accepts
+fn parse_memory_size(text: &str) -> Option<usize> {
+    let (digits, scale) = match text.chars().last()? {
+        'k' | 'K' => (&text[..text.len() - 1], 1024),
+        'm' | 'M' => (&text[..text.len() - 1], 1024 * 1024),
+        _ => (text, 1),
+    };
+    let bytes = usize::try_from(parse_number(digits)?).ok()?.checked_mul(scale)?;
+    validate_memory_size(bytes).ok()
+}
+
+// Labels and source lines: the file given with --symbols, else the image's
+// own symbol section, else a .sym file sitting next to the .v
+fn load_symbols(options: &Options, image: &Image) -> Result<Option<Symbols>, SymbolError> {
+    if let Some(path) = &options.symbols {
+        return Symbols::from_file(path).map(Some);
+    }
+    if let Some(bytes) = &image.symbols {
+        return Symbols::parse(&String::from_utf8_lossy(bytes)).map(Some);
+    }
+    let sidecar = Path::new(&options.file_path).with_extension("sym");
+    if sidecar.is_file() {
+        return Symbols::from_file(sidecar).map(Some);
+    }
+    Ok(None)
+}
 
 fn main() {
     // Command line arguments
     let args: Vec<String> = env::args().collect();
     
     // Check if a filename was provided
-    if args.len() < 2 || args.len() > 2 {
-        println!("Usage: {} <file.v>", args[0]);
-        return;
-    }
-    
-    // Use the first argument as the file path
-    let file_path = &args[1];
-    // println!("File path: {}", file_path);
-
-    // Open the file
-    let mut f = match File::open(file_path) {
-        Ok(file) => file,
-        Err(e) => {
-            eprintln!("Error opening file: {}", e);
+    let options = match parse_args(&args) {
+        Some(options) => options,
+        None => {
+            usage(&args[0]);
             return;
         }
     };
-    let mut buffer = Vec::new();
 
-    // read the whole file
-    match f.read_to_end(&mut buffer) {
-        Ok(_bytes_read) => {
-        },
-        Err(e) => eprintln!("Error reading file: {}", e),
-    }
-
-    // Check to see if magic bytes (0xde, 0xad, 0xbe, 0xef) are present
-    if buffer.len() < 4 {
-        println!("File is too small to contain magic bytes.");
-        return;
-    } else {
-        let magic_bytes = &buffer[0..4];
-        if magic_bytes != b"\xde\xad\xbe\xef" {
-            println!("ERROR: magic doesn't match: {:?}", magic_bytes);
+    // Read the file and check the magic bytes
+    let image = match Image::from_file(&options.file_path) {
+        Ok(image) => image,
+        Err(e @ (LoadError::Open(_) | LoadError::Read(_))) => {
+            eprintln!("{}", e);
+            return;
+        }
+        Err(e @ LoadError::TooSmall) => {
+            println!("{}", e);
+            return;
+        }
+        Err(e) => {
+            println!("{}", e);
             exit(1);
-            // ERROR: magic doesn't match [52, 10, 54, 10].
         }
-    }
+    };
 
-    // Remove the magic bytes from the buffer
-    let buffer = &buffer[4..];
+    let symbols = match load_symbols(&options, &image) {
+        Ok(symbols) => symbols,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
 
-    // Create a new machine and load the buffer
-    let mut m = machine::Machine::new();
-    m.load_bytes(buffer);
+    if options.disassemble {
+        print!("{}", disassemble_with_symbols(&image.code, symbols.as_ref()));
+        return;
+    }
 
-    //todo: After machine initialization we should probably have the machine run from machine.rs
+    // Create a new machine and load the image
+    let mut m = Machine::with_memory(options.memory);
+    if let Err(e) = m.load_image(&image) {
+        println!("{}", e);
+        exit(1);
+    }
+    m.set_symbols(symbols);
+    m.set_max_steps(options.max_steps);
+    m.set_loop_detection(options.detect_loops);
+    m.set_strict(options.strict);
+    m.set_code_protection(options.protect_code);
+    m.set_arithmetic_mode(options.arithmetic);
 
-    m.run();
+    match &options.trace {
+        Some(None) => m.set_tracer(Some(Tracer::stderr())),
+        Some(Some(path)) => match Tracer::to_file(path) {
+            Ok(tracer) => m.set_tracer(Some(tracer)),
+            Err(e) => {
+                eprintln!("Error creating trace file: {}", e);
+                return;
+            }
+        },
+        None => {}
+    }
 
-    // Decode instructions from buffer (for now)
-    let mut instructions = Vec::new();
-    for chunk in buffer.chunks(4) {
-        if let Some(instruction) = Instruction::decode_instruction(chunk) {
-            //println!("Running...");
-            instructions.push(instruction);
+    // Map why the machine stopped onto our own exit status
+    let reason = if options.debug {
+        let mut debugger = Debugger::new();
+        for address in &options.breakpoints {
+            match parse_address(address, &m) {
+                Some(pc) => debugger.add_breakpoint(pc),
+                None => {
+                    eprintln!("Bad breakpoint address: {}", address);
+                    exit(1);
+                }
+            }
+        }
+        for target in options.watchpoints {
+            debugger.add_watchpoint(target);
+        }
+        debugger.run(&mut m)
+    } else {
+        m.run()
+    };
+    // Point at where the program went wrong or was stopped
+    if let Some(pc) = reason.pc() {
+        match m.symbols().and_then(|symbols| symbols.describe(pc)) {
+            Some(location) => eprintln!("Machine halted: {} <{}>", reason, location),
+            None => eprintln!("Machine halted: {}", reason),
         }
     }
-
-    //println!("Decoded Instructions: {:?}", instructions);
-
-}
\ No newline at end of file
+    // and how it got there, if it didn't stop the normal way. Runaway recursion
+    // has a frame for every call, only the innermost ones are worth reading.
+    if !matches!(reason, ExitReason::Exit(_) | ExitReason::EndOfProgram) {
+        let backtrace = m.backtrace();
+        let mut frames = backtrace.lines();
+        for frame in frames.by_ref().take(BACKTRACE_FRAMES) {
+            eprintln!("{}", frame);
+        }
+        let more = frames.count();
+        if more > 0 {
+            eprintln!("  ... {} more", more);
+        }
+    }
+    let _ = m.flush_output();
+    if let Some(tracer) = m.tracer_mut() {
+        let _ = tracer.flush();
+    }
+    exit(reason.code());
+}
@@ -0,0 +1,146 @@
+// Computer Science 365: VM Project, Machine
+// Mike Hall, Seth Nelson, Sarah Pastor, Alan Saucer
+
+// Debug info: labels and the .asm line every instruction came from
+// Read from a sidecar .sym file next to the .v, or from the symbol section
+// of a container. One entry per line, addresses in hex or decimal:
+//
+//   # calc.sym
+//   label Menu 0x0010
+//   line 0x0010 calc.asm 12
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::helper::parse_number;
+
+#[derive(Debug)]
+pub enum SymbolError {
+    Read(io::Error),
+    Parse { line: usize, text: String },    // line is 1-based
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SymbolError::Read(e) => write!(f, "Error reading symbol file: {}", e),
+            SymbolError::Parse { line, text } => write!(f, "bad symbol entry on line {}: {}", line, text),
+        }
+    }
+}
+
+impl std::error::Error for SymbolError {}
+
+// Where in the .asm source an instruction was written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLine {
+    pub file: String,
+    pub line: usize,
+}
+
+impl fmt::Display for SourceLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Symbols {
+    labels: BTreeMap<usize, Vec<String>>,   // Several labels can share an address
+    lines: BTreeMap<usize, SourceLine>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn parse(text: &str) -> Result<Symbols, SymbolError> {
+        let mut symbols = Symbols::new();
+        for (index, line) in text.lines().enumerate() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let parsed = match words.as_slice() {
+                [] => Some(()),
+                [comment, ..] if comment.starts_with('#') => Some(()),
+                ["label", name, address] => parse_address(address).map(|address| symbols.add_label(name, address)),
+                ["line", address, file, number] => parse_address(address)
+                    .zip(number.parse().ok())
+                    .map(|(address, number)| symbols.add_line(address, file, number)),
+                _ => None,
+            };
+            if parsed.is_none() {
+                return Err(SymbolError::Parse { line: index + 1, text: line.to_string() });
+            }
+        }
+        Ok(symbols)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Symbols, SymbolError> {
+        Symbols::parse(&fs::read_to_string(path).map_err(SymbolError::Read)?)
+    }
+
+    pub fn add_label(&mut self, name: &str, address: usize) {
+        self.labels.entry(address).or_default().push(name.to_string());
+    }
+
+    pub fn add_line(&mut self, address: usize, file: &str, line: usize) {
+        self.lines.insert(address, SourceLine { file: file.to_string(), line });
+    }
+
+    // Address a label names
+    pub fn address_of(&self, name: &str) -> Option<usize> {
+        self.labels
+            .iter()
+            .find(|(_, names)| names.iter().any(|label| label == name))
+            .map(|(address, _)| *address)
+    }
+
+    // Labels placed exactly at address
+    pub fn labels_at(&self, address: usize) -> &[String] {
+        self.labels.get(&address).map_or(&[], |names| names.as_slice())
+    }
+
+    // Closest label at or before address, and how far past it address is
+    pub fn nearest_label(&self, address: usize) -> Option<(&str, usize)> {
+        let (start, names) = self.labels.range(..=address).next_back()?;
+        Some((names.last()?.as_str(), address - start))
+    }
+
+    pub fn line_at(&self, address: usize) -> Option<&SourceLine> {
+        self.lines.get(&address)
+    }
+
+    // ex: Menu+8 calc.asm:14, None if nothing is known about address
+    pub fn describe(&self, address: usize) -> Option<String> {
+        let label = self.nearest_label(address).map(|(name, offset)| match offset {
+            0 => name.to_string(),
+            _ => format!("{}+{}", name, offset),
+        });
+        let line = self.line_at(address).map(|line| line.to_string());
+        match (label, line) {
+            (Some(label), Some(line)) => Some(format!("{} {}", label, line)),
+            (label, line) => label.or(line),
+        }
+    }
+
+    // Back to the text format parse reads
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for (address, names) in &self.labels {
+            for name in names {
+                text.push_str(&format!("label {} 0x{:04x}\n", name, address));
+            }
+        }
+        for (address, line) in &self.lines {
+            text.push_str(&format!("line 0x{:04x} {} {}\n", address, line.file, line.line));
+        }
+        text
+    }
+}
+
+fn parse_address(text: &str) -> Option<usize> {
+    usize::try_from(parse_number(text)?).ok()
+}
@@ -0,0 +1,76 @@
+// Computer Science 365: VM Project, Machine
+// Mike Hall, Seth Nelson, Sarah Pastor, Alan Saucer
+
+// Instruction-level execution trace
+// One line per executed instruction, written somewhere other than stdout so
+// it never mixes with the program's output.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::instruction::Instruction;
+use crate::io::MachineIo;
+use crate::machine::{Machine, Step};
+
+// How many values from the top of the stack each line shows
+const TOP_WORDS: usize = 4;
+
+pub struct Tracer {
+    out: Box<dyn Write>,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>) -> Self {
+        Self { out }
+    }
+
+    pub fn stderr() -> Self {
+        Self::new(Box::new(io::stderr()))
+    }
+
+    pub fn to_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(Box::new(BufWriter::new(File::create(path)?))))
+    }
+
+    // Log one step. word and sp_before are the instruction's word and the stack
+    // pointer from before it ran.
+    // ex: 0x0010  f0000005  push 5              sp 0x1000 -> 0x0ffc  top [5]  <Menu+4 calc.asm:13>
+    pub fn record<I: MachineIo>(&mut self, machine: &Machine<I>, step: &Step, word: u32, sp_before: usize) {
+        if let Some(instruction) = &step.instruction {
+            self.record_instruction(machine, step, instruction, word, sp_before);
+        }
+        if let Some(reason) = &step.halted {
+            let _ = writeln!(self.out, "halted: {}", reason);
+        }
+    }
+
+    fn record_instruction<I: MachineIo>(&mut self, machine: &Machine<I>, step: &Step, instruction: &Instruction, word: u32, sp_before: usize) {
+        let mut top = Vec::new();
+        for address in (step.stack_pointer..machine.memory_size()).step_by(4).take(TOP_WORDS) {
+            top.push(machine.peek(address).to_string());
+        }
+
+        // Where we are in the source, when there are symbols
+        let location = match machine.symbols().and_then(|symbols| symbols.describe(step.pc)) {
+            Some(location) => format!("  <{}>", location),
+            None => String::new(),
+        };
+
+        let _ = writeln!(
+            self.out,
+            "0x{:04x}  {:08x}  {:<20}sp 0x{:04x} -> 0x{:04x}  top [{}]{}",
+            step.pc,
+            word,
+            instruction.to_string(),
+            sp_before,
+            step.stack_pointer,
+            top.join(", "),
+            location
+        );
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}